@@ -12,6 +12,7 @@ topic_selection = Topic Selection
 topic-selection-description = Here below is a list of active update topics available for early adoption.
     Select one or more topic to enroll in update testing, deselect to withdraw and rollback to stable packages.
     Use arrow keys to navigate and use Enter to select/deselect.
+topic-selection-details-hint = Press `i` to view the package changes of the highlighted topic.
 topic_selection_closed_topic_warning = Closed/graduated topics detected, ATM will refresh all packages affected by these topics with versions found in the stable repository.
 
 refresh-manifest = Fetching manifest...
@@ -67,6 +68,7 @@ pk_comm_error_mid_tx = PackageKit daemon unexpectedly disconnected or crashed mi
     Error message: {$error}
 pk_comm_error = Unable to communicate with the PackageKit daemon: {$error}
 pk_tx_error = PackageKit daemon reported an error: {$error}
+pk_proxy_error = [!] PackageKit did not accept the configured proxy, continuing without it: {$error}
 pk_comm_no_response = PackageKit daemon did not return a response.
 pk_invalid_id = Package identifier "{$name}" is invalid.
 
@@ -88,6 +90,10 @@ exe_download_error = Unable to download files
 #exe_batch_error = Failed to download packages
 
 apt_finished = APT configuration updated successfully.
+restore = Restore
+restore-finished = The APT configuration from before the change has been restored.
+restore-no-snapshot = No snapshot of the APT configuration is available.
+restore-failure = Failed to restore the previous APT configuration: {$error}
 install_error = An error occurred while installing packages: {$error}
 
 #press_enter_to_return = Press Enter to return to the main menu.
@@ -98,19 +104,92 @@ press_enter_to_bail = Press Enter to return to quit.
 needs-root = Please run me as root!
 topic-table-hint = Selected or enrolled topics are marked with a `*` character.
 fetch-error-fallback = [!] Failed to fetch available topics. Only enrolled topics are shown.
+apply-confirm = Do you want to apply the changes above?
+apply-aborted = Operation aborted, no packages were changed.
+apply-finished = Topic changes have been applied successfully.
+dry-run-sources = The following APT sources would be written:
+dry-run-topic-packages = Installed packages that would be updated from topic `{$topic}`:
+dry-run-dropped-packages = Changes to the packages of the dropped topics, planned against the current package cache:
+dry-run-versions-unavailable = [!] Unable to fetch the package versions of the topic, the list below is incomplete: {$error}
+dry-run-hint = Dry run, no changes were made to your system.
+user = User
+history-empty = No topic changes have been recorded yet.
+history-topics-changed = Topics changed: {$changes} (mirror: {$mirror})
+history-tx-success = Transaction succeeded.
+history-tx-failure = Transaction failed: {$error}
+history-downgraded = Downgraded: {$packages}
+history-restored = Restored the previous APT configuration.
+history-record-failure = [!] The change could not be recorded in the history: {$error}
+undo-nothing = There is no topic change to undo.
+undo-topics-closed = The change cannot be undone, these topics are no longer available: {$topics}
+topic-not-found = Topic `{$name}` is not found in the available topics.
+error-fetch-packages = Failed to fetch the package list of the topic: {$error}.
+diff-installed = Installed
+diff-topic = Topic
+diff-missing = not in topic repository
+diff-not-installed = not installed
+diff-unchanged = up to date
+diff-changed = will be updated
+diff-newer = newer than the topic
+mirror-url = URL
+mirror-latency = Latency
+mirror-throughput = Throughput
+mirror-unreachable = unreachable
+mirror-testing = Testing mirrors ...
+mirror-pinned = Pinned mirror: {$url}
+mirror-ranking-date = Last measured at {$date}, run `atm mirror test` to measure again.
+mirror-ranking-none = The mirrors have not been measured yet, run `atm mirror test` to rank them.
+mirror-not-found = `{$name}` is neither a configured mirror nor a valid mirror URL.
+mirror-set-usage = Please specify either a mirror name or URL, or `--auto`.
+mirror-pin-set = Topics will be fetched from {$url}.
+mirror-pin-cleared = Mirror pin removed, the fastest mirror will be selected automatically.
+mirror-stale = The topic manifest on {$mirror} is out of date, using {$fallback} instead.
+mirror-unreachable-fallback = {$mirror} cannot be reached, using {$fallback} instead.
+manifest-unsigned = The topic manifest from {$mirror} is not signed, refusing to use it.
+    Run ATM with `--allow-unsigned` to use it anyway.
+manifest-bad-signature = The signature of the topic manifest could not be verified: {$error}
+manifest-no-keyring = No trusted archive keyring was found (see `repo.keyrings` in the configuration), unable to verify the topic manifest.
+manifest-too-large = The topic manifest is larger than {$limit} MiB, refusing to use it.
+offline-cached-topics = [!] Offline: using the topic list cached from {$mirror}, stale since {$date}.
+offline-no-cache = No cached topic list is available for offline use.
+offline-no-packages = The packages of topic `{$topic}` have not been cached for offline use.
+mirror-local-no-manifest = No topic manifest was found in the local repository {$mirror}.
+net-error-dns = Unable to resolve the host name of {$url}, please check your network connection and DNS settings.
+net-error-tls = Unable to establish a secure connection to {$url}: {$error}
+net-error-connect = Unable to connect to {$url}: {$error}
+net-error-timeout = {$url} did not respond in time.
+net-error-http = {$url} returned HTTP status {$status}.
+net-error-decode = Malformed response from {$url}: {$error}
+root-no-sync = Packages are not switched in the alternate root {$root}, the topic changes will take effect on the next package upgrade inside it.
+root-needs-command = The interactive interface cannot manage an alternate root, please specify a command.
+topic-invalid-name = `{$name}` is not a valid topic name.
+mirror-not-allowed = {$mirror} is not an allowed mirror, add it to `repo.allowed_mirrors` in /etc/atm/config.toml to use it.
+refresh-unsafe-file = Refusing to read the topic list {$path}: {$reason}
+refresh-not-regular-file = not a regular file
+refresh-wrong-owner = not owned by the requesting user
+refresh-unsafe-permissions = writable by other users or hard linked
+refresh-hash-mismatch = The checksum of the topic list does not match.
+topic-did-you-mean = Did you mean `{$suggestion}`?
+topic-closed = Topic `{$name}` is no longer available on the mirror.
+mirror-invalid-url = `{$url}` is not a valid mirror, only http, https and file URLs or absolute paths are supported.
+state-repaired = The enrolled topics did not match the APT sources (possibly due to an interrupted change) and have been repaired.
+state-unreadable = [!] The list of enrolled topics could not be read and has been rebuilt from the APT sources.
+state-stub-topics = [!] The details of the following topics have been lost, they are shown without description or packages: {$topics}
+state-check-failed = Unable to check the enrolled topics against the APT sources: {$error}
+list-conflicting-format = `--json` cannot be used together with `--format`.
 
 ## Authentication messages
 
 await-authentication = Waiting for authentication to finish ...
 authentication-failure = Authentication failed: {$reason}
+helper-not-authorized = Not authorized to change the topics.
+escalation-method = Requesting root privileges with {$method} ...
+escalation-method-used = Root privileges were obtained with {$method}.
 -run-me-as-root-workaround = Your system is not modified.
 
     Please run ATM as root in your terminal to workaround this issue.
 sudo-failure = ATM can not find any privilege escalation facilities in your system.
 
     { -run-me-as-root-workaround }
-headless-sudo-unsupported = ATM does not support headless privilege escalation.
-
-    { -run-me-as-root-workaround }
 
 file-header = Generated by AOSC Topic Manager. DO NOT EDIT THIS FILE!
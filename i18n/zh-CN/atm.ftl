@@ -11,6 +11,7 @@ description = 描述
 topic_selection = 选择尝鲜分支
 topic-selection-description = 如下是当前可用于测试的尝鲜分支列表。选中一个或多个尝鲜分支即可获得测试用更新，
     反选即可回滚软件包到稳定版本。请使用方向键浏览，并用回车键 (Enter) 选择分支。
+topic-selection-details-hint = 按下 `i` 键可查看当前高亮尝鲜分支的软件包变更。
 topic_selection_closed_topic_warning = 检测到已关闭或已合并的尝鲜分支，ATM 将会把受影响的包回滚到稳定版本。
 
 refresh-manifest = 正在下载分支信息……
@@ -54,6 +55,7 @@ pk_comm_error_mid_tx = PackageKit 守护程序连接丢失或在执行任务时
     错误信息：{$error}
 pk_comm_error = 无法与 PackageKit 守护程序通信：{$error}
 pk_tx_error = PackageKit 守护程序报错：{$error}
+pk_proxy_error = [!] PackageKit 未接受配置的代理服务器，将不使用代理继续：{$error}
 pk_comm_no_response = PackageKit 守护程序无响应。
 pk_invalid_id = 包名 "{$name}" 无效。
 
@@ -74,6 +76,10 @@ exe_download_error = 无法下载文件
 #exe_batch_error = 无法下载软件包
 
 apt_finished = APT 配置信息更新成功。
+restore = 恢复
+restore-finished = 已恢复更改前的 APT 配置。
+restore-no-snapshot = 没有可用的 APT 配置快照。
+restore-failure = 无法恢复先前的 APT 配置：{$error}
 install_error = 安装软件包时发生错误：{$error}
 
 #press_enter_to_return = 请按 Enter 键返回主菜单。
@@ -84,19 +90,92 @@ press_enter_to_bail = 请按 Enter 键退出程序。
 needs-root = 请使用 root 用户运行本程序！
 topic-table-hint = `*` 标记代表已选中或订阅该尝鲜分支。
 fetch-error-fallback = [!] 无法获取可用尝鲜分支列表，目前只显示了已订阅的尝鲜分支。
+apply-confirm = 是否应用上述更改？
+apply-aborted = 操作已取消，未更改任何软件包。
+apply-finished = 尝鲜分支更改已成功应用。
+dry-run-sources = 将写入如下 APT 软件源配置：
+dry-run-topic-packages = 如下已安装的软件包将从尝鲜分支 `{$topic}` 更新：
+dry-run-dropped-packages = 基于当前软件包缓存，已退出测试源中的软件包将做如下变更：
+dry-run-versions-unavailable = [!] 无法获取测试源的软件包版本，以下列表不完整：{$error}
+dry-run-hint = 试运行模式，未对系统进行任何更改。
+user = 用户
+history-empty = 尚无尝鲜分支更改记录。
+history-topics-changed = 尝鲜分支变更：{$changes}（镜像源：{$mirror}）
+history-tx-success = 任务执行成功。
+history-tx-failure = 任务执行失败：{$error}
+history-downgraded = 已降级：{$packages}
+history-restored = 已恢复先前的 APT 配置。
+history-record-failure = [!] 无法将此次更改记入历史记录：{$error}
+undo-nothing = 没有可撤销的尝鲜分支更改。
+undo-topics-closed = 无法撤销该更改，以下尝鲜分支已不再提供：{$topics}
+topic-not-found = 未在可用尝鲜分支中找到 `{$name}`。
+error-fetch-packages = 无法获取尝鲜分支的软件包列表：{$error}。
+diff-installed = 已安装版本
+diff-topic = 分支版本
+diff-missing = 不在分支软件源中
+diff-not-installed = 未安装
+diff-unchanged = 已是最新
+diff-changed = 将更新
+diff-newer = 比分支版本更新
+mirror-url = 地址
+mirror-latency = 延迟
+mirror-throughput = 速度
+mirror-unreachable = 无法访问
+mirror-testing = 正在测试镜像源 ……
+mirror-pinned = 已固定镜像源：{$url}
+mirror-ranking-date = 上次测速于 {$date}，可运行 `atm mirror test` 重新测速。
+mirror-ranking-none = 尚未对镜像源测速，请运行 `atm mirror test` 进行测速。
+mirror-not-found = `{$name}` 既不是已配置的镜像源，也不是有效的镜像源地址。
+mirror-set-usage = 请指定镜像源名称或地址，或使用 `--auto`。
+mirror-pin-set = 将从 {$url} 获取尝鲜分支信息。
+mirror-pin-cleared = 已取消固定镜像源，将自动选择最快的镜像源。
+mirror-stale = 镜像源 {$mirror} 上的尝鲜分支信息已过时，将改用 {$fallback}。
+mirror-unreachable-fallback = 无法连接到镜像源 {$mirror}，将改用 {$fallback}。
+manifest-unsigned = 来自 {$mirror} 的尝鲜分支信息未经签名，已拒绝使用。
+    如需继续使用，请使用 `--allow-unsigned` 参数运行 ATM。
+manifest-bad-signature = 无法验证尝鲜分支信息的签名：{$error}
+manifest-no-keyring = 未找到受信任的软件源密钥环（参见配置文件中的 `repo.keyrings`），无法验证尝鲜分支信息。
+manifest-too-large = 尝鲜分支列表超过 {$limit} MiB，拒绝使用。
+offline-cached-topics = [!] 离线模式：正在使用从 {$mirror} 缓存的尝鲜分支列表，该列表自 {$date} 起未更新。
+offline-no-cache = 没有可供离线使用的尝鲜分支列表缓存。
+offline-no-packages = 尝鲜分支 `{$topic}` 的软件包信息尚未缓存，无法离线使用。
+mirror-local-no-manifest = 未在本地软件源 {$mirror} 中找到尝鲜分支信息。
+net-error-dns = 无法解析 {$url} 的主机名，请检查网络连接及 DNS 设置。
+net-error-tls = 无法与 {$url} 建立安全连接：{$error}
+net-error-connect = 无法连接到 {$url}：{$error}
+net-error-timeout = {$url} 响应超时。
+net-error-http = {$url} 返回了 HTTP 状态码 {$status}。
+net-error-decode = {$url} 返回的数据格式有误：{$error}
+root-no-sync = 未在备用根目录 {$root} 中切换软件包，尝鲜分支更改将在其中下次升级软件包时生效。
+root-needs-command = 交互界面无法管理备用根目录，请指定要执行的命令。
+topic-invalid-name = `{$name}` 不是有效的尝鲜分支名称。
+mirror-not-allowed = {$mirror} 不在允许使用的镜像源列表中，如需使用，请将其加入 /etc/atm/config.toml 中的 `repo.allowed_mirrors`。
+refresh-unsafe-file = 拒绝读取尝鲜分支列表 {$path}：{$reason}
+refresh-not-regular-file = 不是普通文件
+refresh-wrong-owner = 文件不属于发起请求的用户
+refresh-unsafe-permissions = 文件可被其他用户写入或存在硬链接
+refresh-hash-mismatch = 尝鲜分支列表的校验和不匹配。
+topic-did-you-mean = 您是否想要 `{$suggestion}`？
+topic-closed = 尝鲜分支 `{$name}` 已不在镜像源上提供。
+mirror-invalid-url = `{$url}` 不是有效的镜像源，仅支持 http、https、file 地址或绝对路径。
+state-repaired = 已加入的测试源与 APT 软件源配置不一致（可能由于上次更改被中断），现已修复。
+state-unreadable = [!] 无法读取已加入的尝鲜分支列表，已根据 APT 软件源配置重建。
+state-stub-topics = [!] 以下尝鲜分支的信息已丢失，显示时将不含描述及软件包：{$topics}
+state-check-failed = 无法检查已加入的测试源与 APT 软件源配置是否一致：{$error}
+list-conflicting-format = `--json` 不能与 `--format` 同时使用。
 
 ## Authentication messages
 
 await-authentication = 正在等待认证 ……
 authentication-failure = 认证失败：{$reason}
+helper-not-authorized = 未获得更改尝鲜分支的授权。
+escalation-method = 正在通过 {$method} 获取 root 权限 ……
+escalation-method-used = 已通过 {$method} 获取 root 权限。
 -run-me-as-root-workaround = 目前的更改尚未生效。
 
     请使用 root 用户运行 ATM 解决此问题。
 sudo-failure = ATM 无法找到任何可用的提权工具。
 
     { -run-me-as-root-workaround }
-headless-sudo-unsupported = ATM 不支持无交互提权。
-
-    { -run-me-as-root-workaround }
 
 file-header = 本文件由 AOSC Topic Manager 生成，请勿修改！
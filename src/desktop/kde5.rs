@@ -29,10 +29,10 @@ trait JobViewV2 {
     #[zbus(name = "setDescriptionField")]
     fn set_description_field(&self, number: u32, name: &str, value: &str) -> zbus::Result<bool>;
 
-    /// setDestUrl method
+    // setDestUrl method
     // fn set_dest_url(&self, destUrl: &zbus::zvariant::Value<'_>) -> zbus::Result<()>;
 
-    /// setError method
+    // setError method
     // fn set_error(&self, errorCode: u32) -> zbus::Result<()>;
 
     /// setInfoMessage method
@@ -46,7 +46,7 @@ trait JobViewV2 {
     /// setProcessedAmount method
     fn set_processed_amount(&self, amount: u64, unit: &str) -> zbus::Result<()>;
 
-    /// setSpeed method
+    // setSpeed method
     // fn set_speed(&self, bytesPerSecond: u64) -> zbus::Result<()>;
 
     /// setSuspended method
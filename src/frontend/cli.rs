@@ -1,11 +1,22 @@
-use std::{fs::File, io::Read, path::Path, process};
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::Read,
+    path::{Path, PathBuf},
+    process,
+    str::FromStr,
+    sync::mpsc::Receiver,
+};
 
 use anyhow::{anyhow, Result};
 use argh::FromArgs;
+use serde::Serialize;
 use sha2::Digest;
 
-use super::format_timestamp;
-use crate::{fl, network, pm};
+use super::privilege::run_privileged;
+use super::{format_datetime, format_package_diff, format_timestamp, show_tx_details};
+use crate::history::{self, HistoryEvent, HistoryPackage};
+use crate::{config, fl, helper, mirror, network, pk, pm};
 
 #[derive(FromArgs, PartialEq, Debug)]
 /// enroll into a new topic
@@ -14,6 +25,9 @@ pub(crate) struct TopicAdd {
     /// name of the topic
     #[argh(positional)]
     pub name: Vec<String>,
+    /// only show the changes that would be made, do not modify the system
+    #[argh(switch, short = 'n')]
+    pub dry_run: bool,
 }
 
 #[derive(FromArgs, PartialEq, Debug)]
@@ -23,6 +37,9 @@ pub(crate) struct TopicRemove {
     /// name of the topic
     #[argh(positional)]
     pub name: Vec<String>,
+    /// only show the changes that would be made, do not modify the system
+    #[argh(switch, short = 'n')]
+    pub dry_run: bool,
 }
 
 #[derive(FromArgs, PartialEq, Debug)]
@@ -38,12 +55,142 @@ pub(crate) struct RefreshList {
     /// mirror URL to use for the topic list file (optional)
     #[argh(option, short = 'm')]
     pub mirror: Option<String>,
+    /// restore the APT configurations from before the last change
+    #[argh(switch)]
+    pub restore: bool,
+    /// use the cached topics instead of the network (same as the global `--offline`)
+    #[argh(switch)]
+    pub offline: bool,
 }
 
 #[derive(FromArgs, PartialEq, Debug)]
 /// list current topics and available topics
 #[argh(subcommand, name = "list")]
-pub(crate) struct TopicList {}
+pub(crate) struct TopicList {
+    /// output format: table, json or tsv (default: table, see `ui.list_format`)
+    #[argh(option)]
+    pub format: Option<ListFormat>,
+    /// print the topics as JSON (same as `--format json`)
+    #[argh(switch)]
+    pub json: bool,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub(crate) enum ListFormat {
+    Table,
+    Json,
+    Tsv,
+}
+
+impl FromStr for ListFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "table" => Ok(ListFormat::Table),
+            "json" => Ok(ListFormat::Json),
+            "tsv" => Ok(ListFormat::Tsv),
+            _ => Err(format!(
+                "unknown format `{}`, expected table, json or tsv",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// apply topic changes and synchronize packages with the enrolled topics
+#[argh(subcommand, name = "apply")]
+pub(crate) struct TopicApply {
+    /// enroll into the specified topic (can be repeated)
+    #[argh(option, short = 'a')]
+    pub add: Vec<String>,
+    /// exit from the specified topic (can be repeated)
+    #[argh(option, short = 'r')]
+    pub remove: Vec<String>,
+    /// do not ask for confirmation before applying the changes
+    #[argh(switch, short = 'y')]
+    pub yes: bool,
+    /// only show the transaction plan, do not modify the system
+    #[argh(switch, short = 'n')]
+    pub dry_run: bool,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// show the package changes of a topic compared to the installed packages
+#[argh(subcommand, name = "show")]
+pub(crate) struct TopicShow {
+    /// name of the topic
+    #[argh(positional)]
+    pub name: String,
+    /// print the package changes as JSON
+    #[argh(switch)]
+    pub json: bool,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// revert the last topic change and synchronize packages
+#[argh(subcommand, name = "undo")]
+pub(crate) struct TopicUndo {
+    /// do not ask for confirmation before applying the changes
+    #[argh(switch, short = 'y')]
+    pub yes: bool,
+    /// only show the transaction plan, do not modify the system
+    #[argh(switch, short = 'n')]
+    pub dry_run: bool,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// show the history of topic changes
+#[argh(subcommand, name = "history")]
+pub(crate) struct TopicHistory {
+    /// print the history as JSON
+    #[argh(switch)]
+    pub json: bool,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// inspect and select the mirror used to fetch topics
+#[argh(subcommand, name = "mirror")]
+pub(crate) struct MirrorCommand {
+    #[argh(subcommand)]
+    pub command: MirrorSubCommand,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand)]
+pub(crate) enum MirrorSubCommand {
+    List(MirrorList),
+    Test(MirrorTest),
+    Set(MirrorSet),
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// list the configured mirrors with their last measured ranking
+#[argh(subcommand, name = "list")]
+pub(crate) struct MirrorList {}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// measure the latency and throughput of the configured mirrors
+#[argh(subcommand, name = "test")]
+pub(crate) struct MirrorTest {}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// pin the mirror used to fetch topics
+#[argh(subcommand, name = "set")]
+pub(crate) struct MirrorSet {
+    /// name of a configured mirror or URL of the mirror
+    #[argh(positional)]
+    pub mirror: Option<String>,
+    /// remove the pinned mirror and select the fastest mirror automatically
+    #[argh(switch)]
+    pub auto: bool,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// run the privileged D-Bus helper (started by D-Bus activation)
+#[argh(subcommand, name = "helper")]
+pub(crate) struct HelperCommand {}
 
 #[derive(FromArgs, PartialEq, Debug)]
 #[argh(subcommand)]
@@ -52,46 +199,151 @@ pub(crate) enum ATMCommand {
     Refresh(RefreshList),
     Add(TopicAdd),
     Remove(TopicRemove),
+    Apply(TopicApply),
+    History(TopicHistory),
+    Undo(TopicUndo),
+    Show(TopicShow),
+    Mirror(MirrorCommand),
+    Helper(HelperCommand),
+}
+
+impl ATMCommand {
+    /// Whether the command only previews the changes
+    fn is_dry_run(&self) -> bool {
+        match self {
+            ATMCommand::Add(args) => args.dry_run,
+            ATMCommand::Remove(args) => args.dry_run,
+            ATMCommand::Apply(args) => args.dry_run,
+            ATMCommand::Undo(args) => args.dry_run,
+            _ => false,
+        }
+    }
+
+    /// Whether the command may write the APT sources or the state file
+    fn writes_state(&self) -> bool {
+        match self {
+            ATMCommand::Refresh(_) | ATMCommand::Helper(_) => true,
+            ATMCommand::Add(args) => !args.dry_run,
+            ATMCommand::Remove(args) => !args.dry_run,
+            ATMCommand::Apply(args) => !args.dry_run,
+            ATMCommand::Undo(args) => !args.dry_run,
+            _ => false,
+        }
+    }
 }
 
 #[derive(FromArgs, PartialEq, Debug)]
+#[allow(clippy::upper_case_acronyms)]
 /// AOSC Topic Manager
 pub(crate) struct ATM {
+    /// path of the configuration file (default: /etc/atm/config.toml)
+    #[argh(option)]
+    pub config: Option<PathBuf>,
+    /// manage the system installed in this directory, e.g. an image build root,
+    /// only the APT configuration is changed
+    #[argh(option)]
+    pub root: Option<PathBuf>,
+    /// accept topic manifests that are not signed (insecure)
+    #[argh(switch)]
+    pub allow_unsigned: bool,
+    /// use the topics and packages cached by the last successful fetch instead of the network
+    #[argh(switch)]
+    pub offline: bool,
     #[argh(subcommand)]
     pub command: Option<ATMCommand>,
 }
 
 // === end of argh constructs
 
+/// Machine-readable topic record, the field names are part of the public interface
+#[derive(Serialize, Debug)]
+struct TopicRecord<'a> {
+    name: &'a str,
+    enabled: bool,
+    closed: bool,
+    date: i64,
+    arch: Vec<&'a str>,
+    packages: &'a [String],
+    description: Option<&'a str>,
+}
+
+impl<'a> From<&'a network::TopicManifest> for TopicRecord<'a> {
+    fn from(topic: &'a network::TopicManifest) -> Self {
+        let mut arch = topic.arch.iter().map(|a| a.as_str()).collect::<Vec<_>>();
+        arch.sort_unstable();
+
+        TopicRecord {
+            name: &topic.name,
+            enabled: topic.enabled,
+            closed: topic.closed,
+            date: topic.date,
+            arch,
+            packages: &topic.packages,
+            description: topic.description.as_deref(),
+        }
+    }
+}
+
+/// Make the state file follow the APT sources after an interrupted change
+fn repair_state() {
+    match pm::check_consistency() {
+        Ok(pm::StateRepair::Consistent) => (),
+        Ok(pm::StateRepair::Repaired { unreadable, stubs }) => {
+            eprintln!("{}", fl!("state-repaired"));
+            if unreadable {
+                eprintln!("{}", fl!("state-unreadable"));
+            }
+            if !stubs.is_empty() {
+                eprintln!("{}", fl!("state-stub-topics", topics = stubs.join(", ")));
+            }
+        }
+        Err(e) => eprintln!("{}", fl!("state-check-failed", error = e.to_string())),
+    }
+}
+
+/// Check the permission to write the configuration, the configuration of an alternate root
+/// only needs to be writable
 #[inline]
 fn needs_root() -> Result<()> {
-    use nix::unistd::geteuid;
+    if config::get().root.is_some() {
+        return Ok(());
+    }
 
-    if !geteuid().is_root() {
-        Err(anyhow!(fl!("needs-root")))
-    } else {
+    needs_euid_root()
+}
+
+/// Check that atm runs as root, whether or not an alternate root is managed
+#[inline]
+fn needs_euid_root() -> Result<()> {
+    if nix::unistd::geteuid().is_root() {
         Ok(())
+    } else {
+        Err(anyhow!(fl!("needs-root")))
     }
 }
 
-/// Escalate permissions using Polkit-1 and write configuration file
+/// Escalate permissions and write configuration file
+///
+/// The request is sent to the D-Bus helper, or to the configured escalation tools
+/// if the helper is not installed. The privileged side reads the system configuration and
+/// does not accept unsigned manifests, only `--offline` is passed on to the escalation tools
+/// (after `refresh`, which the polkit action matches as the first argument).
 pub fn privileged_write_source_list(
     topics: &[&network::TopicManifest],
     mirror_url: &str,
 ) -> Result<()> {
     use nix::unistd::geteuid;
     use std::io::Write;
-    use std::process::{Command, Stdio};
     use tempfile::NamedTempFile;
 
     if geteuid().is_root() {
         // already root
-        return pm::write_source_list(topics, mirror_url);
+        return pm::write_source_list(topics, mirror_url, history::requesting_uid());
     }
-    if std::env::var("DISPLAY").is_err() {
-        return Err(anyhow!(fl!("headless-sudo-unsupported")));
+    let names = topics.iter().map(|t| t.name.as_str()).collect::<Vec<_>>();
+    if helper::request_set_topics(&names, mirror_url)? {
+        return Ok(());
     }
-    let my_name = std::env::current_exe()?;
     let xfer_content = serde_json::to_vec(topics)?;
     // calculate hash and pass the hash to the privileged process prevent hijack attacks
     let mut chksum = sha2::Sha256::new();
@@ -101,32 +353,93 @@ pub fn privileged_write_source_list(
     let mut f = NamedTempFile::new()?;
     f.write_all(&xfer_content)?;
     // pass the temporary file to the privileged process
-    let cmd = Command::new("pkexec")
-        .arg(my_name)
-        .args(&["refresh", "-c", chksum.as_str(), "-m", mirror_url, "-f"])
-        .arg(f.path())
-        .stderr(Stdio::piped())
-        .stdout(Stdio::null())
-        .spawn()
-        .map_err(|_| anyhow!(fl!("sudo-failure")))?
-        .wait_with_output()?;
-    if !cmd.status.success() {
-        let error_message = std::str::from_utf8(&cmd.stderr).unwrap_or_default();
-        return Err(anyhow!(fl!(
-            "authentication-failure",
-            reason = error_message
-        )));
+    let mut args: Vec<&std::ffi::OsStr> = vec![
+        "refresh".as_ref(),
+        "-c".as_ref(),
+        chksum.as_ref(),
+        "-m".as_ref(),
+        mirror_url.as_ref(),
+        "-f".as_ref(),
+        f.path().as_os_str(),
+    ];
+    if network::is_offline() {
+        args.push("--offline".as_ref());
+    }
+
+    run_privileged(args)
+}
+
+/// Escalate permissions and restore the configuration files
+/// from before the last change
+pub fn privileged_restore_source_list() -> Result<()> {
+    use nix::unistd::geteuid;
+
+    if geteuid().is_root() {
+        return pm::restore_source_list(history::requesting_uid());
+    }
+    if helper::request_restore()? {
+        return Ok(());
     }
 
+    run_privileged(["refresh", "--restore"])
+}
+
+/// Record the outcome of a PackageKit transaction through the D-Bus helper
+///
+/// The escalation tools are not used here, the user would be asked for a password
+/// again right after the transaction, so the outcome is not recorded without the helper.
+pub fn privileged_record_transaction(
+    downgraded: Vec<HistoryPackage>,
+    result: &Result<()>,
+) -> Result<()> {
+    use nix::unistd::geteuid;
+
+    if geteuid().is_root() {
+        return history::record_transaction(downgraded, result, history::requesting_uid());
+    }
+    helper::request_record_transaction(&downgraded, result)?;
+
     Ok(())
 }
 
-async fn fetch_available_topics() -> Result<network::TopicManifests> {
-    let client = network::create_http_client()?;
-    let mirror_url = network::get_best_mirror_url(&client).await;
-    let topics = network::fetch_topics(&client, &mirror_url).await?;
+/// Fetch the topics available for this architecture, returns the topics and the mirror to use
+async fn fetch_available_topics(
+    client: &reqwest::Client,
+) -> Result<(network::TopicManifests, String)> {
+    let mirror_url = mirror::get_best_mirror_url(client).await;
+    let fetched = mirror::load_topics(client, &mirror_url).await?;
+    if let Some(cached) = fetched.cached {
+        eprintln!(
+            "{}",
+            fl!(
+                "offline-cached-topics",
+                mirror = fetched.mirror_url.as_str(),
+                date = format_datetime(cached).unwrap_or_else(|_| "?".to_string())
+            )
+        );
+    }
+    if let Some(unreachable) = &fetched.unreachable_mirror {
+        eprintln!(
+            "{}",
+            fl!(
+                "mirror-unreachable-fallback",
+                mirror = unreachable.as_str(),
+                fallback = fetched.mirror_url.as_str()
+            )
+        );
+    }
+    if let Some(stale) = &fetched.stale_mirror {
+        eprintln!(
+            "{}",
+            fl!(
+                "mirror-stale",
+                mirror = stale.as_str(),
+                fallback = fetched.mirror_url.as_str()
+            )
+        );
+    }
 
-    network::filter_topics(topics)
+    Ok((network::filter_topics(fetched.topics)?, fetched.mirror_url))
 }
 
 fn format_manifests(topics: network::TopicManifests) {
@@ -155,45 +468,154 @@ fn format_manifests(topics: network::TopicManifests) {
     formatter.flush().unwrap();
 }
 
-async fn list_topics() {
-    let mut fallback = false;
-    eprint!("{}", fl!("refresh-manifest"));
-    let available = fetch_available_topics().await.unwrap_or_else(|_| {
-        fallback = true;
-        Vec::new()
-    });
+fn format_manifests_json(topics: &[network::TopicManifest]) -> Result<()> {
+    use std::io::Write;
+
+    let records = topics.iter().map(TopicRecord::from).collect::<Vec<_>>();
+    let stdout = std::io::stdout();
+    let mut stdout = stdout.lock();
+    serde_json::to_writer_pretty(&mut stdout, &records)?;
+    writeln!(stdout)?;
+
+    Ok(())
+}
+
+#[inline]
+fn sanitize_tsv_field(field: &str) -> String {
+    field.replace(['\t', '\n', '\r'], " ")
+}
+
+fn format_manifests_tsv(topics: &[network::TopicManifest]) -> Result<()> {
+    use std::io::Write;
+
+    let stdout = std::io::stdout();
+    let mut stdout = stdout.lock();
+    writeln!(
+        stdout,
+        "name\tenabled\tclosed\tdate\tarch\tpackages\tdescription"
+    )?;
+    for topic in topics {
+        let record = TopicRecord::from(topic);
+        writeln!(
+            stdout,
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            sanitize_tsv_field(record.name),
+            record.enabled,
+            record.closed,
+            record.date,
+            record.arch.join(","),
+            record.packages.join(","),
+            sanitize_tsv_field(record.description.unwrap_or_default())
+        )?;
+    }
+
+    Ok(())
+}
+
+async fn list_topics(format: ListFormat) -> Result<()> {
+    if format == ListFormat::Table {
+        eprintln!("{}", fl!("refresh-manifest"));
+    }
+    let client = network::create_http_client()?;
+    let (available, fetch_error) = match fetch_available_topics(&client).await {
+        Ok((topics, _)) => (topics, None),
+        Err(e) => (Vec::new(), Some(e)),
+    };
     let mut topics = pm::get_display_listing(available);
     topics.sort_unstable_by_key(|t| t.date + if t.enabled { 1_000_000_000 } else { 0 });
-    eprint!("\r\t\t\r"); // clear display
-    format_manifests(topics);
-    if fallback {
-        eprintln!("{}", fl!("fetch-error-fallback"));
-    } else {
-        eprintln!("\n{}", fl!("topic-table-hint"));
+    match format {
+        ListFormat::Table => {
+            format_manifests(topics);
+            if fetch_error.is_some() {
+                eprintln!("{}", fl!("fetch-error-fallback"));
+            } else {
+                eprintln!("\n{}", fl!("topic-table-hint"));
+            }
+        }
+        ListFormat::Json | ListFormat::Tsv => {
+            if format == ListFormat::Json {
+                format_manifests_json(&topics)?;
+            } else {
+                format_manifests_tsv(&topics)?;
+            }
+            // scripts must not mistake the enrolled topics for the complete list
+            if let Some(e) = fetch_error {
+                eprintln!("{}", fl!("fetch-error-fallback"));
+                return Err(e);
+            }
+        }
     }
+
+    Ok(())
 }
 
-fn refresh_topics<P: AsRef<Path>>(
+/// Open the topic list handed over by the unprivileged process, the file must be a regular
+/// file owned by the requesting user (or root) and writable only by its owner
+fn open_handoff_file(path: &Path) -> Result<File> {
+    use nix::fcntl::OFlag;
+    use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+
+    let unsafe_file = |reason: String| {
+        anyhow!(fl!(
+            "refresh-unsafe-file",
+            path = path.display().to_string(),
+            reason = reason
+        ))
+    };
+    // do not follow symbolic links, do not block on FIFOs
+    let f = File::options()
+        .read(true)
+        .custom_flags((OFlag::O_NOFOLLOW | OFlag::O_NONBLOCK).bits())
+        .open(path)
+        .map_err(|e| unsafe_file(e.to_string()))?;
+    let metadata = f.metadata()?;
+    let owner = history::requesting_uid().as_raw();
+    if !metadata.file_type().is_file() {
+        return Err(unsafe_file(fl!("refresh-not-regular-file")));
+    }
+    if metadata.uid() != owner && metadata.uid() != 0 {
+        return Err(unsafe_file(fl!("refresh-wrong-owner")));
+    }
+    if metadata.mode() & 0o022 != 0 || metadata.nlink() != 1 {
+        return Err(unsafe_file(fl!("refresh-unsafe-permissions")));
+    }
+
+    Ok(f)
+}
+
+/// Read the topic names from the topic list handed over by the unprivileged process
+fn read_handoff_topics(path: &Path, chksum: &Option<String>) -> Result<Vec<String>> {
+    let mut f = open_handoff_file(path)?;
+    let mut buffer = Vec::with_capacity(1024);
+    f.read_to_end(&mut buffer)?;
+    if let Some(chksum) = chksum {
+        let mut hasher = sha2::Sha256::new();
+        hasher.update(&buffer);
+        if &format!("{:02x}", hasher.finalize()) != chksum {
+            return Err(anyhow!(fl!("refresh-hash-mismatch")));
+        }
+    }
+    // only the names are used, the topics are taken from the verified manifest
+    let topics: network::TopicManifests = serde_json::from_slice(&buffer)?;
+
+    Ok(topics.into_iter().map(|t| t.name).collect())
+}
+
+async fn refresh_topics<P: AsRef<Path>>(
     filename: Option<P>,
     chksum: &Option<String>,
     mirror_url: Option<String>,
 ) -> Result<()> {
     needs_root()?;
+    let mirror_url = mirror_url.unwrap_or_else(mirror::get_sensible_mirror_url);
     let topics = match filename {
         Some(filename) => {
-            let mut f = File::open(filename)?;
-            let mut buffer = Vec::new();
-            buffer.reserve(1024);
-            f.read_to_end(&mut buffer)?;
-            if let Some(chksum) = chksum {
-                let mut hasher = sha2::Sha256::new();
-                hasher.update(&buffer);
-                if &format!("{:02x}", hasher.finalize()) != chksum {
-                    return Err(anyhow!("Hash mismatch."));
-                }
-            }
+            let names = read_handoff_topics(filename.as_ref(), chksum)?;
+            mirror::check_allowed_mirror(&mirror_url)?;
+            let names = names.iter().map(|n| n.as_str()).collect::<Vec<_>>();
+            let client = network::create_http_client()?;
 
-            serde_json::from_slice(&buffer)?
+            pm::resolve_topics(&client, &mirror_url, &names).await?
         }
         None => {
             let mut topics = pm::get_display_listing(Vec::new());
@@ -203,48 +625,719 @@ fn refresh_topics<P: AsRef<Path>>(
         }
     };
     let topics_ref = topics.iter().collect::<Vec<_>>();
-    let mirror_url = mirror_url.unwrap_or_else(network::get_sensible_mirror_url);
-    pm::write_source_list(&topics_ref, &mirror_url)?;
+    pm::write_source_list(&topics_ref, &mirror_url, history::requesting_uid())?;
     println!("{}", fl!("apt_finished"));
 
     Ok(())
 }
 
-async fn add_topics(topics_to_add: &[String]) -> Result<()> {
+/// Returns the edit distance between the two strings
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut row = (0..=b.len()).collect::<Vec<_>>();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = diagonal + usize::from(ca != *cb);
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(diagonal + 1);
+        }
+    }
+
+    row[b.len()]
+}
+
+/// Returns the topic name closest to the misspelled or incomplete name, if any is close enough
+fn suggest_topic<'a>(topics: &'a [network::TopicManifest], name: &str) -> Option<&'a str> {
+    let threshold = (name.len() / 3).max(2);
+    topics
+        .iter()
+        .filter(|t| !t.closed)
+        .map(|t| (edit_distance(&t.name, name), t.name.as_str()))
+        .filter(|(distance, topic)| *distance <= threshold || topic.starts_with(name))
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, topic)| topic)
+}
+
+/// Check that all the requested topics are in the listing and still available
+fn check_requested_topics(topics: &[network::TopicManifest], requested: &[String]) -> Result<()> {
+    for name in requested {
+        match topics.iter().find(|t| &t.name == name) {
+            Some(topic) if topic.closed => {
+                return Err(anyhow!(fl!("topic-closed", name = name.as_str())))
+            }
+            Some(_) => continue,
+            None => (),
+        }
+        if !pm::is_valid_topic_name(name) {
+            return Err(anyhow!(fl!("topic-invalid-name", name = name.as_str())));
+        }
+        let mut message = fl!("topic-not-found", name = name.as_str());
+        if let Some(suggestion) = suggest_topic(topics, name) {
+            message.push(' ');
+            message.push_str(&fl!("topic-did-you-mean", suggestion = suggestion));
+        }
+        return Err(anyhow!(message));
+    }
+
+    Ok(())
+}
+
+/// Mark the requested changes in the topic listing,
+/// returns the topics to be dropped and the topics to be newly enrolled
+fn mark_topic_changes(
+    topics: &mut [network::TopicManifest],
+    topics_to_add: &[String],
+    topics_to_remove: &[String],
+) -> (network::TopicManifests, network::TopicManifests) {
+    let mut dropped = Vec::new();
+    let mut enrolled = Vec::new();
+    for topic in topics.iter_mut() {
+        if topic.enabled && topics_to_remove.contains(&topic.name) {
+            topic.enabled = false;
+            dropped.push(topic.clone());
+        } else if !topic.enabled && !topic.closed && topics_to_add.contains(&topic.name) {
+            topic.enabled = true;
+            enrolled.push(topic.clone());
+        }
+    }
+
+    (dropped, enrolled)
+}
+
+/// Resolve the stable versions of the packages from the dropped topics
+/// and simulate the resulting transaction, returns (packages without stable version, transaction steps)
+async fn plan_transaction(
+    proxy: &pk::PackageKitProxy<'_>,
+    reinstall: &[network::TopicManifest],
+    refresh: bool,
+) -> Result<(Vec<String>, Vec<pk::PkPackage>)> {
+    let switched = if refresh {
+        pm::switch_topics(proxy, reinstall).await
+    } else {
+        pm::resolve_switch_topics(proxy, reinstall).await
+    };
+    let (not_found, tasks) =
+        switched.map_err(|e| anyhow!(fl!("pk_tx_error", error = e.to_string())))?;
+    let tx = pk::create_transaction(proxy)
+        .await
+        .map_err(|e| anyhow!(fl!("pk_comm_error", error = e.to_string())))?;
+    let tasks = tasks.iter().map(|t| t.as_str()).collect::<Vec<_>>();
+    let steps = pk::get_transaction_steps(&tx, &tasks)
+        .await
+        .map_err(|e| anyhow!(fl!("pk_tx_error", error = e.to_string())))?;
+
+    Ok((not_found, steps))
+}
+
+async fn connect_system_bus() -> Result<zbus::Connection> {
+    pk::create_dbus_connection()
+        .await
+        .map_err(|e| anyhow!(fl!("pk_dbus_error", error = e.to_string())))
+}
+
+/// Print the would-be sources list and transaction plan without modifying the system
+async fn simulate_changes(
+    topics: &[network::TopicManifest],
+    reinstall: &[network::TopicManifest],
+    enrolled: &[network::TopicManifest],
+    mirror_url: &str,
+) -> Result<()> {
+    let topics_ref = topics.iter().filter(|t| t.enabled).collect::<Vec<_>>();
+    println!("{}", fl!("dry-run-sources"));
+    println!("{}", pm::preview_source_list(&topics_ref, mirror_url)?);
+    if let Some(root) = &config::get().root {
+        // PackageKit only manages the running system
+        print_enrolled_packages(enrolled, mirror_url).await?;
+        eprintln!("{}", fl!("root-no-sync", root = root.display().to_string()));
+        eprintln!("{}", fl!("dry-run-hint"));
+        return Ok(());
+    }
+
+    let dbus_connection = connect_system_bus().await?;
+    let proxy = pk::connect_packagekit(&dbus_connection)
+        .await
+        .map_err(|e| anyhow!(fl!("pk_comm_error", error = e.to_string())))?;
+    let (not_found, steps) = plan_transaction(&proxy, reinstall, false).await?;
+    let details = pk::get_task_details(&not_found, &steps)
+        .map_err(|e| anyhow!(fl!("pk_invalid_id", name = e.to_string())))?;
+    println!("{}", fl!("dry-run-dropped-packages"));
+    println!("{}", show_tx_details(&details));
+    print_enrolled_packages(enrolled, mirror_url).await?;
+    eprintln!("{}", fl!("dry-run-hint"));
+
+    Ok(())
+}
+
+/// Print the installed packages that would be updated from the newly enrolled topics
+async fn print_enrolled_packages(
+    enrolled: &[network::TopicManifest],
+    mirror_url: &str,
+) -> Result<()> {
+    if enrolled.is_empty() {
+        return Ok(());
+    }
+    // packages from the newly enrolled topics are not in the package cache yet,
+    // their versions are read from the package indices of the topics
+    let client = network::create_http_client()?;
+    for topic in enrolled {
+        let versions = network::fetch_topic_packages(&client, mirror_url, &topic.name).await;
+        let installed = pm::get_topic_diff(topic, versions.as_ref().unwrap_or(&HashMap::new()))?
+            .into_iter()
+            .filter(|d| d.installed.is_some())
+            .collect::<Vec<_>>();
+        if installed.is_empty() {
+            continue;
+        }
+        println!(
+            "{}",
+            fl!("dry-run-topic-packages", topic = topic.name.as_str())
+        );
+        if let Err(e) = versions {
+            // only the names of the installed packages are known
+            eprintln!(
+                "{}",
+                fl!("dry-run-versions-unavailable", error = e.to_string())
+            );
+            for package in installed {
+                println!("  {}", package.name);
+            }
+            continue;
+        }
+        print!("{}", format_package_diff(&installed)?);
+    }
+
+    Ok(())
+}
+
+fn describe_history_event(event: &HistoryEvent) -> String {
+    match event {
+        HistoryEvent::TopicsChanged {
+            added,
+            removed,
+            mirror,
+            ..
+        } => {
+            let mut changes = added
+                .iter()
+                .map(|t| format!("+{}", t))
+                .chain(removed.iter().map(|t| format!("-{}", t)))
+                .collect::<Vec<_>>()
+                .join(" ");
+            if changes.is_empty() {
+                changes.push('-');
+            }
+            fl!(
+                "history-topics-changed",
+                changes = changes,
+                mirror = mirror.as_str()
+            )
+        }
+        HistoryEvent::Transaction {
+            downgraded,
+            success,
+            error,
+        } => {
+            let downgraded = downgraded
+                .iter()
+                .map(|p| format!("{} ({})", p.name, p.version))
+                .collect::<Vec<_>>()
+                .join(", ");
+            let mut description = if *success {
+                fl!("history-tx-success")
+            } else {
+                fl!(
+                    "history-tx-failure",
+                    error = error.clone().unwrap_or_default()
+                )
+            };
+            if !downgraded.is_empty() {
+                description.push(' ');
+                description += &fl!("history-downgraded", packages = downgraded);
+            }
+            description
+        }
+        HistoryEvent::Restored => fl!("history-restored"),
+    }
+}
+
+fn show_history(json: bool) -> Result<()> {
+    use std::io::Write;
+
+    let entries = history::read_history()?;
+    if json {
+        let stdout = std::io::stdout();
+        let mut stdout = stdout.lock();
+        serde_json::to_writer_pretty(&mut stdout, &entries)?;
+        writeln!(stdout)?;
+        return Ok(());
+    }
+    if entries.is_empty() {
+        eprintln!("{}", fl!("history-empty"));
+        return Ok(());
+    }
+    let mut formatter = tabwriter::TabWriter::new(std::io::stderr());
+    writeln!(
+        &mut formatter,
+        "{}\t{}\t{}",
+        fl!("date"),
+        fl!("user"),
+        fl!("description")
+    )?;
+    for entry in entries {
+        writeln!(
+            &mut formatter,
+            "{}\t{}\t{}",
+            format_datetime(entry.timestamp).unwrap_or_else(|_| "?".to_string()),
+            entry.user,
+            describe_history_event(&entry.event)
+        )?;
+    }
+    formatter.flush()?;
+
+    Ok(())
+}
+
+async fn show_topic(name: &str, json: bool) -> Result<()> {
+    use std::io::Write;
+
+    eprintln!("{}", fl!("refresh-manifest"));
+    let client = network::create_http_client()?;
+    let (available, mirror_url) = fetch_available_topics(&client)
+        .await
+        .map_err(|e| anyhow!(fl!("error-fetch-manifest", error = e.to_string())))?;
+    let topic = available
+        .iter()
+        .find(|t| t.name == name)
+        .ok_or_else(|| anyhow!(fl!("topic-not-found", name = name)))?;
+    let versions = network::fetch_topic_packages(&client, &mirror_url, name)
+        .await
+        .map_err(|e| anyhow!(fl!("error-fetch-packages", error = e.to_string())))?;
+    let diffs = pm::get_topic_diff(topic, &versions)?;
+    if json {
+        let stdout = std::io::stdout();
+        let mut stdout = stdout.lock();
+        serde_json::to_writer_pretty(&mut stdout, &diffs)?;
+        writeln!(stdout)?;
+        return Ok(());
+    }
+    println!(
+        "{}: {}",
+        topic.name,
+        topic.description.as_deref().unwrap_or_default()
+    );
+    print!("{}", format_package_diff(&diffs)?);
+
+    Ok(())
+}
+
+fn restore_topics() -> Result<()> {
     needs_root()?;
+    pm::restore_source_list(history::requesting_uid())?;
+    println!("{}", fl!("restore-finished"));
+
+    Ok(())
+}
+
+async fn add_topics(topics_to_add: &[String], dry_run: bool) -> Result<()> {
+    if !dry_run {
+        needs_root()?;
+    }
     eprintln!("{}", fl!("refresh-manifest"));
     let client = network::create_http_client()?;
-    let mirror_url = network::get_best_mirror_url(&client).await;
-    let available = fetch_available_topics().await?;
+    let (available, mirror_url) = fetch_available_topics(&client).await?;
     let mut topics = pm::get_display_listing(available);
-    for topic in topics.iter_mut() {
-        topic.enabled |= topics_to_add.contains(&topic.name);
+    check_requested_topics(&topics, topics_to_add)?;
+    let (_, enrolled) = mark_topic_changes(&mut topics, topics_to_add, &[]);
+    if dry_run {
+        let reinstall = topics
+            .iter()
+            .filter(|t| t.closed && !t.enabled)
+            .cloned()
+            .collect::<Vec<_>>();
+        return simulate_changes(&topics, &reinstall, &enrolled, &mirror_url).await;
     }
     let topics_ref = topics.iter().filter(|t| t.enabled).collect::<Vec<_>>();
-    pm::write_source_list(&topics_ref, &mirror_url)?;
+    pm::write_source_list(&topics_ref, &mirror_url, history::requesting_uid())?;
     println!("{}", fl!("apt_finished"));
 
     Ok(())
 }
 
-fn remove_topics(topics_to_remove: &[String]) -> Result<()> {
-    needs_root()?;
+async fn remove_topics(topics_to_remove: &[String], dry_run: bool) -> Result<()> {
+    if !dry_run {
+        needs_root()?;
+    }
     let mut topics = pm::get_display_listing(Vec::new());
-    topics
-        .iter_mut()
-        .for_each(|t| t.enabled = !topics_to_remove.contains(&t.name));
-    let topics_ref = topics.iter().collect::<Vec<_>>();
-    pm::write_source_list(&topics_ref, &network::get_sensible_mirror_url())?;
+    topics.iter_mut().for_each(|t| t.enabled = true);
+    let (dropped, _) = mark_topic_changes(&mut topics, &[], topics_to_remove);
+    let mirror_url = mirror::get_sensible_mirror_url();
+    if dry_run {
+        return simulate_changes(&topics, &dropped, &[], &mirror_url).await;
+    }
+    let topics_ref = topics.iter().filter(|t| t.enabled).collect::<Vec<_>>();
+    pm::write_source_list(&topics_ref, &mirror_url, history::requesting_uid())?;
     println!("{}", fl!("apt_finished"));
 
     Ok(())
 }
 
+fn ask_for_confirmation() -> Result<bool> {
+    use std::io::Write;
+
+    eprint!("{} [y/N] ", fl!("apply-confirm"));
+    std::io::stderr().flush()?;
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes" | "Yes"))
+}
+
+/// Print the transaction progress reported by PackageKit until the transaction finishes
+fn print_progress(progress_rx: Receiver<pk::PkDisplayProgress>) {
+    let mut overall = 0;
+    let mut last_message = String::new();
+    while let Ok(progress) = progress_rx.recv() {
+        match progress {
+            pk::PkDisplayProgress::Package(id, status, _) => {
+                let name = pk::humanize_package_id(&id);
+                let status_message = match status {
+                    pk::PK_STATUS_ENUM_DOWNLOAD => fl!("exe_download", name = name),
+                    pk::PK_STATUS_ENUM_INSTALL => fl!("exe-install", name = name),
+                    pk::PK_STATUS_ENUM_SETUP => fl!("exe-setup", name = name),
+                    _ => fl!("exe-install", name = name),
+                };
+                if status_message != last_message {
+                    eprintln!("[{:>3}%] {}", overall, status_message);
+                    last_message = status_message;
+                }
+            }
+            pk::PkDisplayProgress::Overall(pct) => {
+                if pct < 101 {
+                    overall = pct;
+                }
+            }
+            pk::PkDisplayProgress::Done => break,
+        }
+    }
+}
+
+async fn apply_topics(args: &TopicApply) -> Result<()> {
+    if !args.dry_run {
+        needs_root()?;
+    }
+    eprintln!("{}", fl!("refresh-manifest"));
+    let client = network::create_http_client()?;
+    let (available, mirror_url) = fetch_available_topics(&client)
+        .await
+        .map_err(|e| anyhow!(fl!("error-fetch-manifest", error = e.to_string())))?;
+    let mut topics = pm::get_display_listing(available);
+    check_requested_topics(&topics, &args.add)?;
+    let (mut reinstall, enrolled) = mark_topic_changes(&mut topics, &args.add, &args.remove);
+    // closed topics are dropped from the sources list, revert their packages as well
+    reinstall.extend(topics.iter().filter(|t| t.closed && !t.enabled).cloned());
+    if args.dry_run {
+        return simulate_changes(&topics, &reinstall, &enrolled, &mirror_url).await;
+    }
+
+    switch_topics(&topics, &reinstall, &mirror_url, args.yes).await
+}
+
+/// Write the APT configuration for the enabled topics and switch the packages accordingly,
+/// the previous configuration is restored if the packages could not be switched
+async fn switch_topics(
+    topics: &[network::TopicManifest],
+    reinstall: &[network::TopicManifest],
+    mirror_url: &str,
+    yes: bool,
+) -> Result<()> {
+    let topics_ref = topics.iter().filter(|t| t.enabled).collect::<Vec<_>>();
+    pm::write_source_list(&topics_ref, mirror_url, history::requesting_uid())?;
+    eprintln!("{}", fl!("apt_finished"));
+    if let Some(root) = &config::get().root {
+        eprintln!("{}", fl!("root-no-sync", root = root.display().to_string()));
+        return Ok(());
+    }
+
+    let result = sync_packages(reinstall, yes).await;
+    if result.is_err() {
+        // do not leave the new topics in the APT configuration when the packages are not switched
+        match pm::restore_source_list(history::requesting_uid()) {
+            Ok(()) => eprintln!("{}", fl!("restore-finished")),
+            Err(e) => eprintln!("{}", fl!("restore-failure", error = e.to_string())),
+        }
+    }
+
+    result
+}
+
+/// Returns the manifests of the topics to enroll into again,
+/// refuses to enroll into topics which are no longer available
+fn reenrolled_topics(
+    available: network::TopicManifests,
+    names: &[String],
+) -> Result<network::TopicManifests> {
+    let closed = names
+        .iter()
+        .filter(|name| !available.iter().any(|t| &t.name == *name))
+        .map(|name| name.as_str())
+        .collect::<Vec<_>>();
+    if !closed.is_empty() {
+        return Err(anyhow!(fl!(
+            "undo-topics-closed",
+            topics = closed.join(", ")
+        )));
+    }
+
+    Ok(available
+        .into_iter()
+        .filter(|t| names.contains(&t.name))
+        .map(|mut t| {
+            t.enabled = true;
+            t
+        })
+        .collect())
+}
+
+async fn undo_topics(args: &TopicUndo) -> Result<()> {
+    if !args.dry_run {
+        needs_root()?;
+    }
+    let previous =
+        history::get_last_previous_topics()?.ok_or_else(|| anyhow!(fl!("undo-nothing")))?;
+    let mut topics = pm::get_display_listing(Vec::new());
+    topics.iter_mut().for_each(|t| t.enabled = true);
+    let to_remove = topics
+        .iter()
+        .filter(|t| !previous.iter().any(|p| p.name == t.name))
+        .map(|t| t.name.clone())
+        .collect::<Vec<_>>();
+    let to_add = previous
+        .into_iter()
+        .filter(|p| !topics.iter().any(|t| t.name == p.name))
+        .map(|p| p.name)
+        .collect::<Vec<_>>();
+    if to_remove.is_empty() && to_add.is_empty() {
+        return Err(anyhow!(fl!("undo-nothing")));
+    }
+    let (to_add, mirror_url) = if to_add.is_empty() {
+        (Vec::new(), mirror::get_sensible_mirror_url())
+    } else {
+        // the topics enrolled before may have been closed since
+        eprintln!("{}", fl!("refresh-manifest"));
+        let client = network::create_http_client()?;
+        let (available, mirror_url) = fetch_available_topics(&client).await?;
+
+        (reenrolled_topics(available, &to_add)?, mirror_url)
+    };
+    let (reinstall, _) = mark_topic_changes(&mut topics, &[], &to_remove);
+    topics.extend(to_add.iter().cloned());
+    if args.dry_run {
+        return simulate_changes(&topics, &reinstall, &to_add, &mirror_url).await;
+    }
+
+    switch_topics(&topics, &reinstall, &mirror_url, args.yes).await
+}
+
+/// Switch the packages to the topics in the current APT configuration
+async fn sync_packages(reinstall: &[network::TopicManifest], yes: bool) -> Result<()> {
+    eprintln!("{}", fl!("refresh-apt"));
+    let dbus_connection = connect_system_bus().await?;
+    let proxy = pk::connect_packagekit(&dbus_connection)
+        .await
+        .map_err(|e| anyhow!(fl!("pk_comm_error", error = e.to_string())))?;
+    if let Err(e) = pk::forward_proxy(&proxy).await {
+        eprintln!("{}", fl!("pk_proxy_error", error = e.to_string()));
+    }
+    let (not_found, steps) = plan_transaction(&proxy, reinstall, true).await?;
+    let details = pk::get_task_details(&not_found, &steps)
+        .map_err(|e| anyhow!(fl!("pk_invalid_id", name = e.to_string())))?;
+    if steps.is_empty() && details.hold.is_empty() {
+        eprintln!("{}", fl!("nothing"));
+        return Ok(());
+    }
+    eprintln!("{}", show_tx_details(&details));
+    if steps.is_empty() {
+        return Ok(());
+    }
+    if !yes && !ask_for_confirmation()? {
+        return Err(anyhow!(fl!("apply-aborted")));
+    }
+
+    let (progress_tx, progress_rx) = std::sync::mpsc::channel();
+    let printer = std::thread::spawn(move || print_progress(progress_rx));
+    let cookie = pk::take_wake_lock(&dbus_connection, &fl!("pk_inhibit_message"))
+        .await
+        .ok();
+    let transaction = pk::create_transaction(&proxy)
+        .await
+        .map_err(|e| anyhow!(fl!("pk_comm_error", error = e.to_string())))?;
+    let package_ids = steps
+        .iter()
+        .map(|m| m.package_id.as_str())
+        .collect::<Vec<_>>();
+    let result = pk::execute_transaction(&transaction, &package_ids, progress_tx).await;
+    drop(cookie);
+    printer.join().ok();
+    let downgraded = details.downgrade.iter().map(HistoryPackage::from).collect();
+    if let Err(e) = history::record_transaction(downgraded, &result, history::requesting_uid()) {
+        eprintln!("{}", fl!("history-record-failure", error = e.to_string()));
+    }
+    result.map_err(|e| anyhow!(fl!("pk_comm_error_mid_tx", error = e.to_string())))?;
+    eprintln!("{}", fl!("apply-finished"));
+
+    Ok(())
+}
+
+#[inline]
+fn format_throughput(bytes_per_sec: u64) -> String {
+    if bytes_per_sec >= 1024 * 1024 {
+        format!("{:.1} MiB/s", bytes_per_sec as f64 / (1024.0 * 1024.0))
+    } else {
+        format!("{:.1} KiB/s", bytes_per_sec as f64 / 1024.0)
+    }
+}
+
+fn format_mirror_ranking(mirrors: &[mirror::MirrorProbe], pinned: Option<&str>) -> Result<()> {
+    use std::io::Write;
+
+    let mut formatter = tabwriter::TabWriter::new(std::io::stderr());
+    writeln!(
+        &mut formatter,
+        "  {}\t{}\t{}\t{}",
+        fl!("name"),
+        fl!("mirror-url"),
+        fl!("mirror-latency"),
+        fl!("mirror-throughput")
+    )?;
+    for probe in mirrors {
+        let (latency, throughput) = match probe.latency {
+            Some(latency) => (
+                format!("{} ms", latency),
+                probe
+                    .throughput
+                    .map(format_throughput)
+                    .unwrap_or_else(|| "-".to_string()),
+            ),
+            None => (fl!("mirror-unreachable"), "-".to_string()),
+        };
+        writeln!(
+            &mut formatter,
+            "{} {}\t{}\t{}\t{}",
+            if pinned == Some(probe.url.as_str()) {
+                '*'
+            } else {
+                ' '
+            },
+            probe.name,
+            probe.url,
+            latency,
+            throughput
+        )?;
+    }
+    formatter.flush()?;
+
+    Ok(())
+}
+
+fn list_mirrors() -> Result<()> {
+    let configured = mirror::list_mirrors().unwrap_or_default();
+    let pinned = mirror::get_pinned_mirror();
+    let ranking = mirror::read_ranking();
+    let mut mirrors = ranking
+        .as_ref()
+        .map(|r| r.mirrors.clone())
+        .unwrap_or_default();
+    // mirrors added after the last measurement
+    for (name, url) in configured.iter() {
+        if !mirrors.iter().any(|m| &m.url == url) {
+            mirrors.push(mirror::MirrorProbe {
+                name: name.clone(),
+                url: url.clone(),
+                latency: None,
+                throughput: None,
+            });
+        }
+    }
+    format_mirror_ranking(&mirrors, pinned.as_deref())?;
+    eprintln!();
+    if let Some(pinned) = pinned {
+        eprintln!("{}", fl!("mirror-pinned", url = pinned));
+    }
+    match ranking {
+        Some(ranking) => eprintln!(
+            "{}",
+            fl!(
+                "mirror-ranking-date",
+                date = format_datetime(ranking.timestamp).unwrap_or_else(|_| "?".to_string())
+            )
+        ),
+        None => eprintln!("{}", fl!("mirror-ranking-none")),
+    }
+
+    Ok(())
+}
+
+async fn test_mirrors() -> Result<()> {
+    let mut mirrors = mirror::list_mirrors().unwrap_or_default();
+    if mirrors.is_empty() {
+        mirrors.insert(
+            "origin".to_string(),
+            network::default_mirror_url().to_string(),
+        );
+    }
+    eprintln!("{}", fl!("mirror-testing"));
+    let client = network::create_http_client()?;
+    let ranking = mirror::probe_mirrors(&client, &mirrors).await;
+    // only root can update the cache
+    mirror::save_ranking(&ranking).ok();
+    let pinned = mirror::get_pinned_mirror();
+    format_mirror_ranking(&ranking.mirrors, pinned.as_deref())?;
+    if let Some(pinned) = pinned {
+        eprintln!("\n{}", fl!("mirror-pinned", url = pinned));
+    }
+
+    Ok(())
+}
+
+fn set_mirror(args: &MirrorSet) -> Result<()> {
+    let url = match (&args.mirror, args.auto) {
+        (Some(mirror), false) => Some(mirror::resolve_mirror(mirror)?),
+        (None, true) => None,
+        _ => return Err(anyhow!(fl!("mirror-set-usage"))),
+    };
+    needs_root()?;
+    mirror::set_pinned_mirror(url.as_deref())?;
+    match url {
+        Some(url) => eprintln!("{}", fl!("mirror-pin-set", url = url)),
+        None => eprintln!("{}", fl!("mirror-pin-cleared")),
+    }
+
+    Ok(())
+}
+
 /// CLI parser and main function.
 /// Returns `false` if no command-line argument is provided.
 pub fn cli_main() -> bool {
     let args: ATM = argh::from_env();
+    if let Err(e) = config::init(args.config.as_deref(), args.root.as_deref()) {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+    network::set_allow_unsigned(args.allow_unsigned);
+    network::set_offline(args.offline);
+    network::set_no_write(args.command.as_ref().is_some_and(|c| c.is_dry_run()));
+    // an interrupted change is repaired before the next change, the TUI may make one
+    let writes_state = args.command.as_ref().is_none_or(|c| c.writes_state());
+    if writes_state && (config::get().root.is_some() || nix::unistd::geteuid().is_root()) {
+        repair_state();
+    }
     if args.command.is_none() {
+        if args.root.is_some() {
+            eprintln!("{}", fl!("root-needs-command"));
+            process::exit(1);
+        }
         return false;
     }
     let commands = args.command.unwrap();
@@ -253,21 +1346,88 @@ pub fn cli_main() -> bool {
         .build()
         .expect("failed to initialize async runtime");
     match commands {
-        ATMCommand::List(_) => runner.block_on(list_topics()),
+        ATMCommand::List(args) => {
+            if args.json && args.format.is_some() {
+                eprintln!("{}", fl!("list-conflicting-format"));
+                process::exit(1);
+            }
+            let format = if args.json {
+                ListFormat::Json
+            } else {
+                // the configured value is checked when loading the configuration
+                args.format.unwrap_or_else(|| {
+                    ListFormat::from_str(&config::get().ui.list_format).unwrap_or(ListFormat::Table)
+                })
+            };
+            if let Err(e) = runner.block_on(list_topics(format)) {
+                eprintln!("{}", e);
+                process::exit(1);
+            }
+        }
         ATMCommand::Refresh(args) => {
-            if let Err(e) = refresh_topics(args.filename, &args.checksum, args.mirror) {
+            if args.offline {
+                network::set_offline(true);
+            }
+            let result = if args.restore {
+                restore_topics()
+            } else {
+                runner.block_on(refresh_topics(args.filename, &args.checksum, args.mirror))
+            };
+            if let Err(e) = result {
                 eprintln!("{}", e);
                 process::exit(1);
             }
         }
         ATMCommand::Add(topics) => {
-            if let Err(e) = runner.block_on(add_topics(&topics.name)) {
+            if let Err(e) = runner.block_on(add_topics(&topics.name, topics.dry_run)) {
                 eprintln!("{}", e);
                 process::exit(1);
             }
         }
         ATMCommand::Remove(topics) => {
-            if let Err(e) = remove_topics(&topics.name) {
+            if let Err(e) = runner.block_on(remove_topics(&topics.name, topics.dry_run)) {
+                eprintln!("{}", e);
+                process::exit(1);
+            }
+        }
+        ATMCommand::History(args) => {
+            if let Err(e) = show_history(args.json) {
+                eprintln!("{}", e);
+                process::exit(1);
+            }
+        }
+        ATMCommand::Undo(args) => {
+            if let Err(e) = runner.block_on(undo_topics(&args)) {
+                eprintln!("{}", e);
+                process::exit(1);
+            }
+        }
+        ATMCommand::Show(args) => {
+            if let Err(e) = runner.block_on(show_topic(&args.name, args.json)) {
+                eprintln!("{}", e);
+                process::exit(1);
+            }
+        }
+        ATMCommand::Apply(args) => {
+            if let Err(e) = runner.block_on(apply_topics(&args)) {
+                eprintln!("{}", e);
+                process::exit(1);
+            }
+        }
+        ATMCommand::Mirror(args) => {
+            let result = match args.command {
+                MirrorSubCommand::List(_) => list_mirrors(),
+                MirrorSubCommand::Test(_) => runner.block_on(test_mirrors()),
+                MirrorSubCommand::Set(args) => set_mirror(&args),
+            };
+            if let Err(e) = result {
+                eprintln!("{}", e);
+                process::exit(1);
+            }
+        }
+        ATMCommand::Helper(_) => {
+            let result = needs_euid_root().and_then(|_| runner.block_on(helper::run_helper()));
+            if let Err(e) = result {
                 eprintln!("{}", e);
                 process::exit(1);
             }
@@ -276,3 +1436,29 @@ pub fn cli_main() -> bool {
 
     true
 }
+
+#[test]
+fn test_suggest_topic() {
+    use network::test_topic as topic;
+
+    let closed = network::TopicManifest {
+        closed: true,
+        ..topic("kernel-6.5")
+    };
+    let topics = [
+        topic("kernel-6.6"),
+        topic("gnome-46"),
+        topic("rust-1.80"),
+        closed,
+    ];
+    assert_eq!(edit_distance("kernel-6.6", "kernel-6.6"), 0);
+    assert_eq!(edit_distance("kernel-6.6", "kernal-6.6"), 1);
+    assert_eq!(edit_distance("", "abc"), 3);
+    assert_eq!(suggest_topic(&topics, "kernal-6.6"), Some("kernel-6.6"));
+    assert_eq!(suggest_topic(&topics, "gnome"), Some("gnome-46"));
+    assert_eq!(suggest_topic(&topics, "firefox"), None);
+    assert!(check_requested_topics(&topics, &["rust-1.80".to_owned()]).is_ok());
+    assert!(check_requested_topics(&topics, &["rust-1.81".to_owned()]).is_err());
+    assert!(check_requested_topics(&topics, &["kernel-6.5".to_owned()]).is_err());
+    assert_eq!(suggest_topic(&topics, "kernel-6.4"), Some("kernel-6.6"));
+}
@@ -1,12 +1,90 @@
 use anyhow::Result;
+use std::cmp::Ordering;
 use time::{format_description::FormatItem, macros::format_description};
 
+use crate::fl;
+use crate::parser::compare_versions;
+use crate::pk::PkTaskList;
+use crate::pm::PackageDiff;
+
 pub mod cli;
+pub mod privilege;
 pub mod tui;
 
 const DATE_FORMAT: &[FormatItem] = format_description!("[year]-[month repr:numerical]-[day]");
+const DATETIME_FORMAT: &[FormatItem] =
+    format_description!("[year]-[month repr:numerical]-[day] [hour]:[minute]:[second] UTC");
 
 #[inline]
 pub(crate) fn format_timestamp(t: i64) -> Result<String> {
     Ok(time::OffsetDateTime::from_unix_timestamp(t)?.format(&DATE_FORMAT)?)
 }
+
+#[inline]
+pub(crate) fn format_datetime(t: i64) -> Result<String> {
+    Ok(time::OffsetDateTime::from_unix_timestamp(t)?.format(&DATETIME_FORMAT)?)
+}
+
+pub(crate) fn show_tx_details(tasks: &PkTaskList) -> String {
+    let mut listing = String::with_capacity(1024);
+    listing += &fl!("tx_body");
+    listing.push('\n');
+
+    for t in tasks.hold.iter() {
+        listing += &fl!("tx_hold", package = t.name);
+        listing.push('\n');
+    }
+    for t in tasks.erase.iter() {
+        listing += &fl!("tx_erase", package = t.name, version = t.version);
+        listing.push('\n');
+    }
+    for t in tasks.downgrade.iter() {
+        listing += &fl!("tx_downgrade", package = t.name, version = t.version);
+        listing.push('\n');
+    }
+    for t in tasks.upgrade.iter() {
+        listing += &fl!("tx_upgrade", package = t.name, version = t.version);
+        listing.push('\n');
+    }
+    for t in tasks.install.iter() {
+        listing += &fl!("tx_install", package = t.name, version = t.version);
+        listing.push('\n');
+    }
+
+    listing
+}
+
+/// Format the package version changes of a topic as a table
+pub(crate) fn format_package_diff(diffs: &[PackageDiff]) -> Result<String> {
+    use std::io::Write;
+
+    let mut formatter = tabwriter::TabWriter::new(Vec::new());
+    writeln!(
+        &mut formatter,
+        "{}\t{}\t{}\t",
+        fl!("name"),
+        fl!("diff-installed"),
+        fl!("diff-topic")
+    )?;
+    for diff in diffs {
+        let status = match (&diff.installed, &diff.topic) {
+            (_, None) => fl!("diff-missing"),
+            (None, Some(_)) => fl!("diff-not-installed"),
+            (Some(installed), Some(topic)) => match compare_versions(installed, topic) {
+                Ordering::Less => fl!("diff-changed"),
+                Ordering::Equal => fl!("diff-unchanged"),
+                Ordering::Greater => fl!("diff-newer"),
+            },
+        };
+        writeln!(
+            &mut formatter,
+            "{}\t{}\t{}\t{}",
+            diff.name,
+            diff.installed.as_deref().unwrap_or("-"),
+            diff.topic.as_deref().unwrap_or("-"),
+            status
+        )?;
+    }
+
+    Ok(String::from_utf8(formatter.into_inner()?)?)
+}
@@ -0,0 +1,198 @@
+//! Privilege escalation through pkexec, sudo, doas or run0
+
+use std::{
+    io::IsTerminal,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+};
+
+use anyhow::{anyhow, Result};
+
+use crate::{config, fl};
+
+/// Names of the supported escalation methods, in the default order of preference
+pub const ESCALATION_METHODS: [&str; 4] = ["pkexec", "sudo", "doas", "run0"];
+
+static TUI_ACTIVE: AtomicBool = AtomicBool::new(false);
+/// Escalation method used without a prompt on the terminal while the TUI was active
+static UNREPORTED_METHOD: Mutex<Option<&'static str>> = Mutex::new(None);
+
+/// Mark the TUI as active, the terminal is then handed over to the escalation tool
+#[inline]
+pub fn set_tui_active(active: bool) {
+    TUI_ACTIVE.store(active, Ordering::SeqCst);
+}
+
+#[inline]
+fn has_display() -> bool {
+    std::env::var_os("DISPLAY").is_some() || std::env::var_os("WAYLAND_DISPLAY").is_some()
+}
+
+#[inline]
+fn has_terminal() -> bool {
+    std::io::stdin().is_terminal()
+}
+
+/// Returns the escalation method used since the last call which the TUI has to report,
+/// the methods asking for a password on the terminal are reported there
+pub fn take_unreported_method() -> Option<&'static str> {
+    UNREPORTED_METHOD.lock().ok()?.take()
+}
+
+/// Returns the full path of the program if it can be found in `PATH`
+fn find_program(name: &str) -> Option<PathBuf> {
+    find_program_in(name, std::env::var_os("PATH")?.to_str()?)
+}
+
+/// Returns the full path of the executable program in the given search path
+fn find_program_in(name: &str, search_path: &str) -> Option<PathBuf> {
+    use std::os::unix::fs::PermissionsExt;
+
+    search_path
+        .split(':')
+        .map(|dir| Path::new(dir).join(name))
+        .find(|path| {
+            path.metadata()
+                .is_ok_and(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+        })
+}
+
+/// An escalation method usable in the current session
+struct Escalation {
+    name: &'static str,
+    program: PathBuf,
+    /// Whether the method asks for the password on the terminal
+    interactive: bool,
+}
+
+/// Returns the first usable escalation method in the configured order
+fn select_escalation(methods: &[String]) -> Option<Escalation> {
+    methods.iter().find_map(|method| {
+        let name = *ESCALATION_METHODS.iter().find(|m| *m == method)?;
+        // pkexec uses the graphical agent of the session, or its own text agent on a terminal
+        let interactive = !(name == "pkexec" && has_display());
+        if interactive && !has_terminal() {
+            return None;
+        }
+        let program = find_program(name)?;
+
+        Some(Escalation {
+            name,
+            program,
+            interactive,
+        })
+    })
+}
+
+/// Hands the terminal over to the escalation tool while the TUI is active
+///
+/// The TUI uses the alternate screen in raw mode, the password prompts need the normal screen
+/// in canonical mode.
+struct TerminalGuard {
+    termios: Option<nix::sys::termios::Termios>,
+}
+
+impl TerminalGuard {
+    fn new() -> Self {
+        use nix::sys::termios::{tcgetattr, tcsetattr, LocalFlags, SetArg};
+        use std::io::Write;
+
+        if !TUI_ACTIVE.load(Ordering::SeqCst) {
+            return TerminalGuard { termios: None };
+        }
+        let stdin = std::io::stdin();
+        let termios = tcgetattr(&stdin).ok();
+        if let Some(termios) = &termios {
+            let mut cooked = termios.clone();
+            cooked.local_flags |= LocalFlags::ICANON | LocalFlags::ECHO | LocalFlags::ISIG;
+            tcsetattr(&stdin, SetArg::TCSANOW, &cooked).ok();
+        }
+        // leave the alternate screen and show the cursor
+        print!("\x1b[?1049l\x1b[?25h");
+        std::io::stdout().flush().ok();
+
+        TerminalGuard { termios }
+    }
+}
+
+impl Drop for TerminalGuard {
+    fn drop(&mut self) {
+        use nix::sys::termios::{tcsetattr, SetArg};
+        use std::io::Write;
+
+        if !TUI_ACTIVE.load(Ordering::SeqCst) {
+            return;
+        }
+        print!("\x1b[?1049h\x1b[?25l");
+        std::io::stdout().flush().ok();
+        if let Some(termios) = &self.termios {
+            tcsetattr(std::io::stdin(), SetArg::TCSANOW, termios).ok();
+        }
+    }
+}
+
+/// Run this program again with the given arguments as root,
+/// using the first usable escalation method in the configured order
+pub fn run_privileged<I, S>(args: I) -> Result<()>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<std::ffi::OsStr>,
+{
+    let escalation = select_escalation(&config::get().escalation.methods)
+        .ok_or_else(|| anyhow!(fl!("sudo-failure")))?;
+    let my_name = std::env::current_exe()?;
+    let mut cmd = Command::new(&escalation.program);
+    cmd.arg(my_name).args(args).stdout(Stdio::null());
+    let _guard = if escalation.interactive {
+        // the prompt and the errors go to the terminal
+        let guard = TerminalGuard::new();
+        eprintln!("{}", fl!("escalation-method", method = escalation.name));
+        cmd.stdin(Stdio::inherit()).stderr(Stdio::inherit());
+        Some(guard)
+    } else {
+        if !TUI_ACTIVE.load(Ordering::SeqCst) {
+            eprintln!("{}", fl!("escalation-method", method = escalation.name));
+        } else if let Ok(mut method) = UNREPORTED_METHOD.lock() {
+            *method = Some(escalation.name);
+        }
+        cmd.stdin(Stdio::null()).stderr(Stdio::piped());
+        None
+    };
+    let output = cmd
+        .spawn()
+        .map_err(|_| anyhow!(fl!("sudo-failure")))?
+        .wait_with_output()?;
+    if !output.status.success() {
+        let error_message = String::from_utf8_lossy(&output.stderr);
+        let reason = if error_message.trim().is_empty() {
+            output.status.to_string()
+        } else {
+            error_message.trim().to_owned()
+        };
+        return Err(anyhow!(fl!("authentication-failure", reason = reason)));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_select_escalation() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let methods = ["nonexistent-tool".to_owned()];
+    assert!(select_escalation(&methods).is_none());
+    assert!(find_program("sh").is_some());
+    assert!(find_program("nonexistent-tool").is_none());
+
+    let dir = tempfile::tempdir().unwrap();
+    let program = dir.path().join("tool");
+    std::fs::write(&program, "#!/bin/sh\n").unwrap();
+    let search_path = dir.path().to_str().unwrap();
+    assert!(find_program_in("tool", search_path).is_none());
+    std::fs::set_permissions(&program, std::fs::Permissions::from_mode(0o755)).unwrap();
+    assert_eq!(find_program_in("tool", search_path), Some(program));
+}
@@ -10,11 +10,15 @@ use cursive_async_view::AsyncView;
 use cursive_table_view::{TableView, TableViewItem};
 use dashmap::DashMap;
 
-use super::cli::privileged_write_source_list;
-use super::format_timestamp;
+use super::cli::{
+    privileged_record_transaction, privileged_restore_source_list, privileged_write_source_list,
+};
+use super::privilege;
+use super::{format_datetime, format_package_diff, format_timestamp, show_tx_details};
+use crate::history::HistoryPackage;
 use crate::network::{TopicManifest, TopicManifests};
 use crate::pk::{self, PkPackage, PkTaskList};
-use crate::{fl, network, pm};
+use crate::{config, fl, mirror, network, pm};
 
 type MarksMap = DashMap<String, bool>;
 
@@ -79,7 +83,7 @@ impl TUIContext {
         let async_runner = create_async_runner()?;
         let dbus_connection = async_runner.block_on(pk::create_dbus_connection())?;
         let client = network::create_http_client()?;
-        let mirror_url = async_runner.block_on(network::get_best_mirror_url(&client));
+        let mirror_url = async_runner.block_on(mirror::get_best_mirror_url(&client));
 
         Ok(TUIContext {
             async_runner,
@@ -107,6 +111,38 @@ fn show_error(siv: &mut Cursive, msg: &str) {
     );
 }
 
+fn error_dialog_with_restore(msg: &str) -> Dialog {
+    Dialog::around(TextView::new(msg))
+        .title(fl!("error"))
+        .button(fl!("restore"), |s| {
+            s.pop_layer();
+            restore_source_list(s);
+        })
+        .button(fl!("exit"), |s| s.quit())
+        .padding_lrtb(2, 2, 1, 1)
+}
+
+/// Show the error and offer to restore the APT configuration from before the change
+fn show_error_with_restore(siv: &mut Cursive, msg: &str) {
+    siv.add_layer(error_dialog_with_restore(msg));
+}
+
+fn restore_source_list(siv: &mut Cursive) {
+    let result = privileged_restore_source_list();
+    // the escalation tool may have used the terminal
+    siv.clear();
+    match result {
+        Ok(()) => siv.add_layer(
+            Dialog::around(TextView::new(fl!("restore-finished")))
+                .title(fl!("message"))
+                .button(fl!("exit"), |s| s.quit())
+                .padding_lrtb(2, 2, 1, 1),
+        ),
+        Err(e) => show_error(siv, &fl!("restore-failure", error = e.to_string())),
+    }
+    show_escalation_method(siv);
+}
+
 fn show_message(siv: &mut Cursive, msg: &str) {
     siv.add_layer(
         Dialog::around(TextView::new(msg))
@@ -118,17 +154,26 @@ fn show_message(siv: &mut Cursive, msg: &str) {
     );
 }
 
+/// Report the escalation method used without the terminal, if any
+fn show_escalation_method(siv: &mut Cursive) {
+    if let Some(method) = privilege::take_unreported_method() {
+        show_message(siv, &fl!("escalation-method-used", method = method));
+    }
+}
+
 fn show_finished(siv: &mut Cursive) {
     show_message(siv, &fl!("apt_finished"));
 }
 
 fn check_network(siv: &mut Cursive, marks: Arc<MarksMap>) {
     let ctx = siv.user_data::<TUIContext>().unwrap();
-    if !ctx.async_runner.block_on(async {
-        pk::is_metered_network(&ctx.dbus_connection)
-            .await
-            .unwrap_or(false)
-    }) {
+    if !config::get().ui.warn_metered
+        || !ctx.async_runner.block_on(async {
+            pk::is_metered_network(&ctx.dbus_connection)
+                .await
+                .unwrap_or(false)
+        })
+    {
         return check_battery_level(siv, marks);
     }
     siv.add_layer(
@@ -147,11 +192,13 @@ fn check_network(siv: &mut Cursive, marks: Arc<MarksMap>) {
 
 fn check_battery_level(siv: &mut Cursive, marks: Arc<MarksMap>) {
     let ctx = siv.user_data::<TUIContext>().unwrap();
-    if !ctx.async_runner.block_on(async {
-        pk::is_using_battery(&ctx.dbus_connection)
-            .await
-            .unwrap_or(false)
-    }) {
+    if !config::get().ui.warn_battery
+        || !ctx.async_runner.block_on(async {
+            pk::is_using_battery(&ctx.dbus_connection)
+                .await
+                .unwrap_or(false)
+        })
+    {
         return check_changes(siv, marks);
     }
     siv.add_layer(
@@ -168,33 +215,14 @@ fn check_battery_level(siv: &mut Cursive, marks: Arc<MarksMap>) {
     );
 }
 
-fn show_tx_details(tasks: &PkTaskList) -> String {
-    let mut listing = String::with_capacity(1024);
-    listing += &fl!("tx_body");
-    listing.push('\n');
-
-    for t in tasks.hold.iter() {
-        listing += &fl!("tx_hold", package = t.name);
-        listing.push('\n');
-    }
-    for t in tasks.erase.iter() {
-        listing += &fl!("tx_erase", package = t.name, version = t.version);
-        listing.push('\n');
-    }
-    for t in tasks.downgrade.iter() {
-        listing += &fl!("tx_downgrade", package = t.name, version = t.version);
-        listing.push('\n');
+/// Forward the configured proxy to PackageKit, and report if it was refused
+async fn forward_proxy(proxy: &pk::PackageKitProxy<'_>, cb_sink: &cursive::CbSink) {
+    if let Err(e) = pk::forward_proxy(proxy).await {
+        let warning = fl!("pk_proxy_error", error = e.to_string());
+        cb_sink
+            .send(Box::new(move |s| show_message(s, &warning)))
+            .ok();
     }
-    for t in tasks.upgrade.iter() {
-        listing += &fl!("tx_upgrade", package = t.name, version = t.version);
-        listing.push('\n');
-    }
-    for t in tasks.install.iter() {
-        listing += &fl!("tx_install", package = t.name, version = t.version);
-        listing.push('\n');
-    }
-
-    listing
 }
 
 fn commit_transactions(siv: &mut Cursive, packages: &[PkPackage]) {
@@ -208,6 +236,9 @@ fn commit_transactions(siv: &mut Cursive, packages: &[PkPackage]) {
         .iter()
         .map(|m| m.package_id.to_string())
         .collect::<Vec<_>>();
+    let downgraded = pk::get_task_details(&[], packages)
+        .map(|t| t.downgrade.iter().map(HistoryPackage::from).collect())
+        .unwrap_or_default();
     // UI components
     let item_counter = Counter::new(0);
     let overall_counter = Counter::new(0);
@@ -231,19 +262,22 @@ fn commit_transactions(siv: &mut Cursive, packages: &[PkPackage]) {
     // actual execution
     let ctx = siv.user_data::<TUIContext>().unwrap();
     let dbus_connection = ctx.dbus_connection.clone();
-    let transaction_thread = thread::spawn(move || -> Result<()> {
+    // returns the result of the transaction, or an error if it could not be started
+    let warning_sink = cb_sink.clone();
+    let transaction_thread = thread::spawn(move || -> Result<Result<()>> {
         let runner = create_async_runner()?;
         runner.block_on(async {
             let cookie = pk::take_wake_lock(&dbus_connection, &fl!("pk_inhibit_message"))
                 .await
                 .ok();
             let proxy = pk::connect_packagekit(&dbus_connection).await?;
+            forward_proxy(&proxy, &warning_sink).await;
             let transaction = pk::create_transaction(&proxy).await?;
             let package_ids = package_ids.iter().map(|m| m.as_str()).collect::<Vec<_>>();
-            pk::execute_transaction(&transaction, &package_ids, progress_tx).await?;
+            let result = pk::execute_transaction(&transaction, &package_ids, progress_tx).await;
             drop(cookie);
 
-            Ok(())
+            Ok(result)
         })
     });
     thread::spawn(move || {
@@ -280,18 +314,35 @@ fn commit_transactions(siv: &mut Cursive, packages: &[PkPackage]) {
         }
 
         let result = transaction_thread.join().unwrap();
-        match result {
+        // the history is written by the privileged side
+        let record_warning = match &result {
+            Ok(result) => privileged_record_transaction(downgraded, result)
+                .err()
+                .map(|e| fl!("history-record-failure", error = e.to_string())),
+            Err(_) => None,
+        };
+        match result.and_then(|result| result) {
             Ok(()) => cb_sink
                 .send(Box::new(|s| {
                     s.set_autorefresh(false);
                     s.pop_layer();
                     show_finished(s);
+                    if let Some(warning) = record_warning {
+                        show_message(s, &warning);
+                    }
+                    show_escalation_method(s);
                 }))
                 .unwrap(),
             Err(e) => cb_sink
                 .send(Box::new(move |s| {
                     s.set_autorefresh(false);
-                    show_error(s, &fl!("pk_comm_error_mid_tx", error = e.to_string()))
+                    let mut message = fl!("pk_comm_error_mid_tx", error = e.to_string());
+                    if let Some(warning) = record_warning {
+                        message.push_str("\n\n");
+                        message.push_str(&warning);
+                    }
+                    show_error_with_restore(s, &message);
+                    show_escalation_method(s);
                 }))
                 .unwrap(),
         }
@@ -352,6 +403,7 @@ fn show_summary(tasks: PkTaskList, packages: Arc<Vec<PkPackage>>) -> Dialog {
 }
 
 fn calculate_changes(siv: &mut Cursive, reinstall: TopicManifests) {
+    let cb_sink = siv.cb_sink().clone();
     let ctx = siv.user_data::<TUIContext>().unwrap();
     let dbus_connection = ctx.dbus_connection.clone();
     let loader = AsyncView::new_with_bg_creator(
@@ -361,10 +413,12 @@ fn calculate_changes(siv: &mut Cursive, reinstall: TopicManifests) {
             let mut tracker = crate::desktop::select_best_tracker();
             tracker.set_general_description(&fl!("refresh-apt"));
 
-            runner.block_on(async {
+            // errors are handled by the view creator to offer restoring the configuration
+            Ok(runner.block_on(async {
                 let proxy = pk::connect_packagekit(&dbus_connection)
                     .await
                     .map_err(|e| fl!("pk_comm_error", error = e.to_string()))?;
+                forward_proxy(&proxy, &cb_sink).await;
                 let (not_found, tasks) = pm::switch_topics(&proxy, &reinstall)
                     .await
                     .map_err(|e| fl!("pk_tx_error", error = e.to_string()))?;
@@ -377,9 +431,13 @@ fn calculate_changes(siv: &mut Cursive, reinstall: TopicManifests) {
                     .map_err(|e| fl!("pk_tx_error", error = e.to_string()))?;
 
                 Ok((not_found, transaction))
-            })
+            }))
         },
-        |(nf, tx)| {
+        |result: Result<_, String>| {
+            let (nf, tx) = match result {
+                Ok(v) => v,
+                Err(e) => return error_dialog_with_restore(&e),
+            };
             let tx = Arc::new(tx);
             let details = pk::get_task_details(&nf, &tx);
             match details {
@@ -419,7 +477,10 @@ fn check_changes(siv: &mut Cursive, marks: Arc<MarksMap>) {
                 }
             }
 
-            if let Err(e) = privileged_write_source_list(&enabled, &mirror_url) {
+            let result = privileged_write_source_list(&enabled, &mirror_url);
+            // the escalation tool may have used the terminal
+            cb_sink.send(Box::new(|s| s.clear())).unwrap();
+            if let Err(e) = result {
                 let message = e.to_string();
                 cb_sink
                     .send(Box::new(move |s| show_error(s, &message)))
@@ -432,7 +493,52 @@ fn check_changes(siv: &mut Cursive, marks: Arc<MarksMap>) {
                     }))
                     .unwrap();
             }
+            cb_sink.send(Box::new(show_escalation_method)).unwrap();
+        },
+    );
+}
+
+fn show_topic_details(siv: &mut Cursive) {
+    let topic = siv
+        .call_on_name(
+            "topic",
+            |v: &mut TableView<network::TopicManifest, TopicColumn>| {
+                v.item().and_then(|i| v.borrow_item(i).cloned())
+            },
+        )
+        .flatten();
+    let topic = match topic {
+        Some(topic) => topic,
+        None => return,
+    };
+    let ctx = siv.user_data::<TUIContext>().unwrap();
+    let client = ctx.client.clone();
+    let mirror_url = ctx.mirror_url.clone();
+    let title = topic.name.clone();
+    let loader = AsyncView::new_with_bg_creator(
+        siv,
+        move || {
+            let runner = create_async_runner().map_err(|e| e.to_string())?;
+            let versions = runner
+                .block_on(network::fetch_topic_packages(
+                    &client,
+                    &mirror_url,
+                    &topic.name,
+                ))
+                .map_err(|e| fl!("error-fetch-packages", error = e.to_string()))?;
+            let diffs = pm::get_topic_diff(&topic, &versions).map_err(|e| e.to_string())?;
+
+            format_package_diff(&diffs).map_err(|e| e.to_string())
         },
+        |table| TextView::new(table).scrollable().scroll_x(true),
+    );
+    siv.add_layer(
+        Dialog::around(loader)
+            .title(title)
+            .button(fl!("ok"), |s| {
+                s.pop_layer();
+            })
+            .padding_lrtb(2, 2, 1, 1),
     );
 }
 
@@ -479,11 +585,12 @@ fn build_topic_list_view(siv: &mut Cursive, manifest: Vec<TopicManifest>) {
 
     let mut top_view = LinearLayout::vertical();
     top_view.add_child(TextView::new(fl!("topic-selection-description")));
+    top_view.add_child(TextView::new(fl!("topic-selection-details-hint")));
     top_view.add_child(DummyView {});
     if has_closed {
         top_view.add_child(TextView::new(fl!("topic_selection_closed_topic_warning")));
     }
-    top_view.add_child(view.scroll_x(true));
+    top_view.add_child(OnEventView::new(view.scroll_x(true)).on_event('i', show_topic_details));
     siv.pop_layer();
     siv.add_layer(
         Dialog::around(top_view)
@@ -498,13 +605,46 @@ fn fetch_manifest(siv: &mut Cursive) {
     let ctx = siv.user_data::<TUIContext>().unwrap();
     let fetch_result = ctx
         .async_runner
-        .block_on(network::fetch_topics(&ctx.client, &ctx.mirror_url));
-    let filtered_list = fetch_result
-        .and_then(network::filter_topics)
-        .map(pm::get_display_listing);
-    match filtered_list {
+        .block_on(mirror::load_topics(&ctx.client, &ctx.mirror_url));
+    let fetched = match fetch_result {
+        Ok(fetched) => fetched,
+        Err(e) => return show_error(siv, &fl!("error-fetch-manifest", error = e.to_string())),
+    };
+    // the packages must be fetched from the same mirror as the manifest
+    ctx.mirror_url = fetched.mirror_url.clone();
+    match network::filter_topics(fetched.topics).map(pm::get_display_listing) {
         Ok(filtered_list) => build_topic_list_view(siv, filtered_list),
-        Err(e) => show_error(siv, &fl!("error-fetch-manifest", error = e.to_string())),
+        Err(e) => return show_error(siv, &fl!("error-fetch-manifest", error = e.to_string())),
+    }
+    if let Some(cached) = fetched.cached {
+        show_message(
+            siv,
+            &fl!(
+                "offline-cached-topics",
+                mirror = fetched.mirror_url.as_str(),
+                date = format_datetime(cached).unwrap_or_else(|_| "?".to_string())
+            ),
+        );
+    }
+    if let Some(unreachable) = fetched.unreachable_mirror {
+        show_message(
+            siv,
+            &fl!(
+                "mirror-unreachable-fallback",
+                mirror = unreachable,
+                fallback = fetched.mirror_url.as_str()
+            ),
+        );
+    }
+    if let Some(stale) = fetched.stale_mirror {
+        show_message(
+            siv,
+            &fl!(
+                "mirror-stale",
+                mirror = stale,
+                fallback = fetched.mirror_url
+            ),
+        );
     }
 }
 
@@ -538,5 +678,7 @@ fn initialize_context(siv: &mut CursiveRunnable) {
 pub fn tui_main() {
     let mut siv = cursive::default();
     initialize_context(&mut siv);
+    privilege::set_tui_active(true);
     siv.run();
+    privilege::set_tui_active(false);
 }
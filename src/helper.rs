@@ -0,0 +1,307 @@
+//! Privileged D-Bus helper (`io.aosc.atm` on the system bus)
+//!
+//! The helper is started by D-Bus activation and changes the APT configuration on behalf of
+//! unprivileged frontends, after checking the `io.aosc.atm.apply.run` polkit action.
+
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use nix::unistd::Uid;
+use tokio::sync::Mutex;
+use zbus::{
+    fdo, interface, message::Header, names::UniqueName, proxy, zvariant::Value, Connection,
+    Result as zResult,
+};
+
+use crate::history::{self, HistoryPackage};
+use crate::{fl, mirror, network, pm};
+
+const HELPER_BUS_NAME: &str = "io.aosc.atm";
+const HELPER_OBJECT_PATH: &str = "/io/aosc/atm";
+const POLKIT_ACTION_ID: &str = "io.aosc.atm.apply.run";
+const POLKIT_FLAG_ALLOW_USER_INTERACTION: u32 = 1;
+/// The helper exits after this period without any request
+const HELPER_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[proxy(
+    interface = "org.freedesktop.PolicyKit1.Authority",
+    default_service = "org.freedesktop.PolicyKit1",
+    default_path = "/org/freedesktop/PolicyKit1/Authority"
+)]
+trait PolkitAuthority {
+    /// CheckAuthorization method
+    fn check_authorization(
+        &self,
+        subject: &(&str, HashMap<&str, Value<'_>>),
+        action_id: &str,
+        details: HashMap<&str, &str>,
+        flags: u32,
+        cancellation_id: &str,
+    ) -> zResult<(bool, bool, HashMap<String, String>)>;
+}
+
+#[proxy(
+    interface = "io.aosc.atm",
+    default_service = "io.aosc.atm",
+    default_path = "/io/aosc/atm"
+)]
+trait AtmHelper {
+    /// SetTopics method
+    fn set_topics(&self, topics: &[&str], mirror_url: &str) -> zResult<()>;
+
+    /// Restore method
+    fn restore(&self) -> zResult<()>;
+
+    /// RecordTransaction method
+    fn record_transaction(
+        &self,
+        downgraded: &[(&str, &str)],
+        success: bool,
+        error: &str,
+    ) -> zResult<()>;
+}
+
+/// Check that the sender of the request is allowed to change the topics
+async fn check_authorization(
+    conn: &Connection,
+    sender: Option<&UniqueName<'_>>,
+) -> fdo::Result<()> {
+    let sender = sender.ok_or_else(|| fdo::Error::AccessDenied("unknown sender".to_owned()))?;
+    // errors from polkit must not be mistaken for errors from the helper itself
+    let polkit_error = |e: zbus::Error| fdo::Error::AuthFailed(e.to_string());
+    let authority = PolkitAuthorityProxy::new(conn)
+        .await
+        .map_err(polkit_error)?;
+    let subject = (
+        "system-bus-name",
+        HashMap::from([("name", Value::from(sender.as_str()))]),
+    );
+    let (authorized, _, _) = authority
+        .check_authorization(
+            &subject,
+            POLKIT_ACTION_ID,
+            HashMap::new(),
+            POLKIT_FLAG_ALLOW_USER_INTERACTION,
+            "",
+        )
+        .await
+        .map_err(polkit_error)?;
+    if !authorized {
+        return Err(fdo::Error::AccessDenied(fl!("helper-not-authorized")));
+    }
+
+    Ok(())
+}
+
+/// Returns the user who sent the request, as reported by the bus
+async fn caller_uid(conn: &Connection, sender: Option<&UniqueName<'_>>) -> fdo::Result<Uid> {
+    let sender = sender.ok_or_else(|| fdo::Error::AccessDenied("unknown sender".to_owned()))?;
+    let uid = fdo::DBusProxy::new(conn)
+        .await?
+        .get_connection_unix_user(sender.clone().into())
+        .await?;
+
+    Ok(Uid::from_raw(uid))
+}
+
+struct Helper {
+    /// Serializes the requests, holds the time of the last request
+    last_request: Arc<Mutex<Instant>>,
+}
+
+#[interface(name = "io.aosc.atm")]
+impl Helper {
+    /// Enroll into the given topics (and only them) using the mirror, the topics must be
+    /// available on the mirror or already enrolled, the mirror must be allowed
+    async fn set_topics(
+        &self,
+        #[zbus(connection)] conn: &Connection,
+        #[zbus(header)] header: Header<'_>,
+        topics: Vec<String>,
+        mirror_url: String,
+    ) -> fdo::Result<()> {
+        check_authorization(conn, header.sender()).await?;
+        let requester = caller_uid(conn, header.sender()).await?;
+        let mut last_request = self.last_request.lock().await;
+        *last_request = Instant::now();
+        mirror::check_allowed_mirror(&mirror_url)
+            .map_err(|e| fdo::Error::InvalidArgs(e.to_string()))?;
+        let names = topics.iter().map(|t| t.as_str()).collect::<Vec<_>>();
+        let client =
+            network::create_http_client().map_err(|e| fdo::Error::Failed(e.to_string()))?;
+        let topics = pm::resolve_topics(&client, &mirror_url, &names)
+            .await
+            .map_err(|e| fdo::Error::InvalidArgs(e.to_string()))?;
+        let enabled = topics.iter().collect::<Vec<_>>();
+
+        pm::write_source_list(&enabled, &mirror_url, requester)
+            .map_err(|e| fdo::Error::Failed(e.to_string()))
+    }
+
+    /// Restore the APT configuration from before the last change
+    async fn restore(
+        &self,
+        #[zbus(connection)] conn: &Connection,
+        #[zbus(header)] header: Header<'_>,
+    ) -> fdo::Result<()> {
+        check_authorization(conn, header.sender()).await?;
+        let requester = caller_uid(conn, header.sender()).await?;
+        let mut last_request = self.last_request.lock().await;
+        *last_request = Instant::now();
+
+        pm::restore_source_list(requester).map_err(|e| fdo::Error::Failed(e.to_string()))
+    }
+
+    /// Record the outcome of a PackageKit transaction in the history,
+    /// `error` is empty if the transaction succeeded
+    async fn record_transaction(
+        &self,
+        #[zbus(connection)] conn: &Connection,
+        #[zbus(header)] header: Header<'_>,
+        downgraded: Vec<(String, String)>,
+        success: bool,
+        error: String,
+    ) -> fdo::Result<()> {
+        check_authorization(conn, header.sender()).await?;
+        let requester = caller_uid(conn, header.sender()).await?;
+        let mut last_request = self.last_request.lock().await;
+        *last_request = Instant::now();
+        let downgraded = downgraded
+            .into_iter()
+            .map(|(name, version)| HistoryPackage { name, version })
+            .collect();
+        let result = if success { Ok(()) } else { Err(anyhow!(error)) };
+
+        history::record_transaction(downgraded, &result, requester)
+            .map_err(|e| fdo::Error::Failed(e.to_string()))
+    }
+}
+
+/// Serve the helper on the system bus until it is idle (requires root)
+pub async fn run_helper() -> Result<()> {
+    let last_request = Arc::new(Mutex::new(Instant::now()));
+    let helper = Helper {
+        last_request: last_request.clone(),
+    };
+    let _conn = zbus::connection::Builder::system()?
+        .name(HELPER_BUS_NAME)?
+        .serve_at(HELPER_OBJECT_PATH, helper)?
+        .build()
+        .await?;
+    loop {
+        tokio::time::sleep(Duration::from_secs(5)).await;
+        // the lock is held while a request is being processed
+        if let Ok(last_request) = last_request.try_lock() {
+            if last_request.elapsed() >= HELPER_IDLE_TIMEOUT {
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Convert the result of a helper call, returns `Ok(false)` if the helper is not available
+fn helper_result(result: zResult<()>) -> Result<bool> {
+    let result = result.map_err(|e| match e {
+        zbus::Error::FDO(e) => *e,
+        e => fdo::Error::from(e),
+    });
+    match result {
+        Ok(()) => Ok(true),
+        Err(
+            fdo::Error::ServiceUnknown(_)
+            | fdo::Error::NameHasNoOwner(_)
+            | fdo::Error::SpawnExecFailed(_)
+            | fdo::Error::SpawnFailed(_)
+            | fdo::Error::SpawnServiceNotFound(_)
+            | fdo::Error::SpawnChildExited(_),
+        ) => Ok(false),
+        Err(fdo::Error::AccessDenied(reason) | fdo::Error::AuthFailed(reason)) => {
+            Err(anyhow!(fl!("authentication-failure", reason = reason)))
+        }
+        Err(fdo::Error::Failed(error) | fdo::Error::InvalidArgs(error)) => Err(anyhow!(error)),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Requests understood by the helper
+enum HelperRequest<'a> {
+    SetTopics(&'a [&'a str], &'a str),
+    Restore,
+    RecordTransaction(&'a [(&'a str, &'a str)], bool, &'a str),
+}
+
+/// Send the request to the helper, returns `Ok(false)` if the helper is not available
+fn call_helper(request: HelperRequest<'_>) -> Result<bool> {
+    let runner = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    runner.block_on(async {
+        let conn = match Connection::system().await {
+            Ok(conn) => conn,
+            Err(_) => return Ok(false),
+        };
+        let result = async {
+            let proxy = AtmHelperProxy::new(&conn).await?;
+            match request {
+                HelperRequest::SetTopics(topics, mirror_url) => {
+                    proxy.set_topics(topics, mirror_url).await
+                }
+                HelperRequest::Restore => proxy.restore().await,
+                HelperRequest::RecordTransaction(downgraded, success, error) => {
+                    proxy.record_transaction(downgraded, success, error).await
+                }
+            }
+        };
+
+        helper_result(result.await)
+    })
+}
+
+/// Ask the helper to enroll into the topics, returns `Ok(false)` if the helper is not available
+pub fn request_set_topics(topics: &[&str], mirror_url: &str) -> Result<bool> {
+    call_helper(HelperRequest::SetTopics(topics, mirror_url))
+}
+
+/// Ask the helper to restore the APT configuration, returns `Ok(false)` if the helper is not
+/// available
+pub fn request_restore() -> Result<bool> {
+    call_helper(HelperRequest::Restore)
+}
+
+/// Ask the helper to record the outcome of a PackageKit transaction,
+/// returns `Ok(false)` if the helper is not available
+pub fn request_record_transaction(
+    downgraded: &[HistoryPackage],
+    result: &Result<()>,
+) -> Result<bool> {
+    let downgraded = downgraded
+        .iter()
+        .map(|p| (p.name.as_str(), p.version.as_str()))
+        .collect::<Vec<_>>();
+    let error = result
+        .as_ref()
+        .err()
+        .map(|e| e.to_string())
+        .unwrap_or_default();
+
+    call_helper(HelperRequest::RecordTransaction(
+        &downgraded,
+        result.is_ok(),
+        &error,
+    ))
+}
+
+#[test]
+fn test_helper_result() {
+    let error = |e: fdo::Error| Err(zbus::Error::FDO(Box::new(e)));
+    assert!(helper_result(Ok(())).unwrap());
+    assert!(!helper_result(error(fdo::Error::ServiceUnknown(String::new()))).unwrap());
+    assert!(!helper_result(error(fdo::Error::SpawnExecFailed(String::new()))).unwrap());
+    assert!(helper_result(error(fdo::Error::AccessDenied(String::new()))).is_err());
+    let e = helper_result(error(fdo::Error::InvalidArgs("bad mirror".to_owned()))).unwrap_err();
+    assert_eq!(e.to_string(), "bad mirror");
+}
@@ -0,0 +1,213 @@
+//! Topic enrollment history
+
+use std::{
+    fs,
+    io::{BufRead, BufReader, Write},
+};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::config;
+use crate::pk::PkPackgeId;
+use crate::pm::PreviousTopic;
+
+const HISTORY_FILE: &str = "history";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct HistoryPackage {
+    pub name: String,
+    pub version: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub enum HistoryEvent {
+    /// The enrolled topics were changed
+    TopicsChanged {
+        added: Vec<String>,
+        removed: Vec<String>,
+        mirror: String,
+        /// Topics enrolled before the change
+        #[serde(default)]
+        previous: Vec<PreviousTopic>,
+    },
+    /// A PackageKit transaction was executed after a topic change
+    Transaction {
+        downgraded: Vec<HistoryPackage>,
+        success: bool,
+        error: Option<String>,
+    },
+    /// The APT configuration was restored from the snapshot
+    Restored,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct HistoryEntry {
+    pub timestamp: i64,
+    pub user: String,
+    #[serde(flatten)]
+    pub event: HistoryEvent,
+}
+
+impl From<&PkPackgeId<'_>> for HistoryPackage {
+    fn from(package: &PkPackgeId<'_>) -> Self {
+        HistoryPackage {
+            name: package.name.to_string(),
+            version: package.version.to_string(),
+        }
+    }
+}
+
+/// Returns the user who requested the operation
+/// (instead of root when escalated through pkexec, sudo, doas or run0)
+pub fn requesting_uid() -> nix::unistd::Uid {
+    use nix::unistd::{geteuid, Uid, User};
+
+    let uid = ["PKEXEC_UID", "SUDO_UID"]
+        .iter()
+        .find_map(|var| std::env::var(var).ok()?.parse().ok())
+        .map(Uid::from_raw);
+    if let Some(uid) = uid {
+        return uid;
+    }
+    std::env::var("DOAS_USER")
+        .ok()
+        .and_then(|name| User::from_name(&name).ok().flatten())
+        .map(|user| user.uid)
+        .unwrap_or_else(geteuid)
+}
+
+/// Returns the name of the user, or the uid if the user has no name
+fn user_name(uid: nix::unistd::Uid) -> String {
+    match nix::unistd::User::from_uid(uid) {
+        Ok(Some(user)) => user.name,
+        _ => uid.to_string(),
+    }
+}
+
+/// Append an event requested by the given user to the history log (requires root)
+pub fn append_history(event: HistoryEvent, requester: nix::unistd::Uid) -> Result<()> {
+    let entry = HistoryEntry {
+        timestamp: time::OffsetDateTime::now_utc().unix_timestamp(),
+        user: user_name(requester),
+        event,
+    };
+    let mut line = serde_json::to_string(&entry)?;
+    line.push('\n');
+    let paths = &config::get().paths;
+    fs::create_dir_all(&paths.state_dir)?;
+    let mut f = fs::File::options()
+        .append(true)
+        .create(true)
+        .open(paths.state_file(HISTORY_FILE))?;
+    f.write_all(line.as_bytes())?;
+
+    Ok(())
+}
+
+/// Record the outcome of a PackageKit transaction (requires root)
+pub fn record_transaction(
+    downgraded: Vec<HistoryPackage>,
+    result: &Result<()>,
+    requester: nix::unistd::Uid,
+) -> Result<()> {
+    append_history(
+        HistoryEvent::Transaction {
+            downgraded,
+            success: result.is_ok(),
+            error: result.as_ref().err().map(|e| e.to_string()),
+        },
+        requester,
+    )
+}
+
+fn parse_history<R: BufRead>(reader: R) -> Result<Vec<HistoryEntry>> {
+    let mut entries = Vec::new();
+    for line in reader.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        // skip damaged entries (e.g. truncated by a power failure) instead of hiding the whole log
+        if let Ok(entry) = serde_json::from_str(&line) {
+            entries.push(entry);
+        }
+    }
+
+    Ok(entries)
+}
+
+/// Returns all the recorded history entries, oldest first
+pub fn read_history() -> Result<Vec<HistoryEntry>> {
+    match fs::File::open(config::get().paths.state_file(HISTORY_FILE)) {
+        Ok(f) => parse_history(BufReader::new(f)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Whether the last event is a failed transaction, the APT configuration then contains
+/// a change that could not be applied to the packages
+fn ends_with_failure(entries: &[HistoryEntry]) -> bool {
+    matches!(
+        entries.last().map(|e| &e.event),
+        Some(HistoryEvent::Transaction { success: false, .. })
+    )
+}
+
+/// Whether the last topic change failed and has not been restored since
+pub fn last_change_failed() -> Result<bool> {
+    Ok(ends_with_failure(&read_history()?))
+}
+
+/// Returns the topics enrolled before the last recorded topic change
+pub fn get_last_previous_topics() -> Result<Option<Vec<PreviousTopic>>> {
+    let entries = read_history()?;
+    let previous = entries.into_iter().rev().find_map(|e| match e.event {
+        HistoryEvent::TopicsChanged { previous, .. } => Some(previous),
+        _ => None,
+    });
+
+    Ok(previous)
+}
+
+#[test]
+fn test_history_format() {
+    let entry = HistoryEntry {
+        timestamp: 1700000000,
+        user: "tester".to_string(),
+        event: HistoryEvent::TopicsChanged {
+            added: vec!["kernel-6.6".to_string()],
+            removed: vec![],
+            mirror: "https://repo.aosc.io/".to_string(),
+            previous: vec![],
+        },
+    };
+    let line = serde_json::to_string(&entry).unwrap();
+    assert_eq!(
+        line,
+        r#"{"timestamp":1700000000,"user":"tester","event":"topics-changed","added":["kernel-6.6"],"removed":[],"mirror":"https://repo.aosc.io/","previous":[]}"#
+    );
+    let log = format!(
+        "{}\n{{\"timestamp\":1,\"user\":\"root\",\"event\":\"restored\"}}\n{{\"timestam\n",
+        line
+    );
+    let entries = parse_history(log.as_bytes()).unwrap();
+    assert_eq!(entries.len(), 2);
+    assert_eq!(entries[0], entry);
+    assert_eq!(entries[1].event, HistoryEvent::Restored);
+    assert!(!ends_with_failure(&entries));
+    let failure = |success| HistoryEntry {
+        timestamp: 2,
+        user: "root".to_string(),
+        event: HistoryEvent::Transaction {
+            downgraded: vec![],
+            success,
+            error: None,
+        },
+    };
+    assert!(ends_with_failure(&[entry.clone(), failure(false)]));
+    assert!(!ends_with_failure(&[entry, failure(true)]));
+    assert!(!ends_with_failure(&[]));
+}
@@ -1,6 +1,10 @@
+mod config;
 mod desktop;
 mod frontend;
+mod helper;
+mod history;
 mod i18n;
+mod mirror;
 mod network;
 mod parser;
 mod pk;
@@ -0,0 +1,606 @@
+//! Mirror probing and selection
+
+use std::{
+    cmp::Reverse,
+    collections::HashSet,
+    fs,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use indexmap::IndexMap;
+use reqwest::{Client, Url};
+use serde::{Deserialize, Serialize};
+
+use crate::network::{self, default_mirror_url, fetch_topics, TopicManifests};
+use crate::{config, fl};
+
+const MIRROR_CACHE_FILE: &str = "mirrors.json";
+const MIRROR_PIN_FILE: &str = "pinned-mirror";
+/// How long a mirror ranking stays valid (in seconds)
+const MIRROR_CACHE_TTL: i64 = 24 * 60 * 60;
+/// Number of requests sent to each mirror when probing
+const PROBE_SAMPLES: usize = 3;
+/// All the mirrors are probed in parallel within this period,
+/// mirrors that have not responded by then are ranked as unreachable
+const PROBE_DEADLINE: Duration = Duration::from_secs(3);
+
+#[derive(Deserialize, Debug)]
+struct AptGenListStatus {
+    mirror: IndexMap<String, String>,
+}
+
+/// Measurements of a single mirror
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct MirrorProbe {
+    pub name: String,
+    pub url: String,
+    /// Median time to the response headers in milliseconds, `None` if the mirror is unreachable
+    pub latency: Option<u64>,
+    /// Download speed of the topic manifest in bytes per second
+    pub throughput: Option<u64>,
+}
+
+/// Mirrors ordered from the best to the worst
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MirrorRanking {
+    pub timestamp: i64,
+    pub mirrors: Vec<MirrorProbe>,
+}
+
+impl MirrorRanking {
+    fn new(mut mirrors: Vec<MirrorProbe>) -> Self {
+        // unreachable mirrors go last, ties in latency are broken by throughput
+        mirrors.sort_by_key(|m| (m.latency.is_none(), m.latency, Reverse(m.throughput)));
+
+        MirrorRanking {
+            timestamp: time::OffsetDateTime::now_utc().unix_timestamp(),
+            mirrors,
+        }
+    }
+
+    /// Whether the ranking is still valid for the given mirror list
+    fn is_fresh(&self, mirrors: &IndexMap<String, String>, now: i64) -> bool {
+        let ranked = self
+            .mirrors
+            .iter()
+            .map(|m| m.url.as_str())
+            .collect::<HashSet<_>>();
+        let configured = mirrors.values().map(|u| u.as_str()).collect::<HashSet<_>>();
+
+        now >= self.timestamp && now - self.timestamp < MIRROR_CACHE_TTL && ranked == configured
+    }
+
+    /// Returns the URL of the best reachable mirror
+    pub fn best(&self) -> Option<&str> {
+        self.mirrors
+            .iter()
+            .find(|m| m.latency.is_some())
+            .map(|m| m.url.as_str())
+    }
+}
+
+/// Returns the mirrors configured in APT (name to URL), in the order of preference
+pub fn list_mirrors() -> Result<IndexMap<String, String>> {
+    let mirrors: AptGenListStatus =
+        serde_json::from_reader(fs::File::open(&config::get().paths.apt_gen_list_status)?)?;
+
+    Ok(mirrors.mirror)
+}
+
+/// Returns the mirror pinned by the user
+pub fn get_pinned_mirror() -> Option<String> {
+    let pinned = fs::read_to_string(config::get().paths.state_file(MIRROR_PIN_FILE)).ok()?;
+    let pinned = pinned.trim();
+    if pinned.is_empty() {
+        return None;
+    }
+
+    Some(pinned.to_owned())
+}
+
+/// Pin the mirror to use, or clear the pin with `None` (requires root)
+pub fn set_pinned_mirror(url: Option<&str>) -> Result<()> {
+    let paths = &config::get().paths;
+    let pin_path = paths.state_file(MIRROR_PIN_FILE);
+    match url {
+        Some(url) => {
+            fs::create_dir_all(&paths.state_dir)?;
+            fs::write(pin_path, format!("{}\n", url))?;
+        }
+        None => match fs::remove_file(pin_path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => (),
+        },
+    }
+
+    Ok(())
+}
+
+/// Resolve a mirror name from the APT configuration, a mirror URL or the path of a local
+/// repository
+pub fn resolve_mirror(mirror: &str) -> Result<String> {
+    if let Some(url) = list_mirrors().ok().and_then(|m| m.get(mirror).cloned()) {
+        return Ok(url);
+    }
+    // local repository, e.g. on a USB disk or an NFS share
+    if let Ok(path) = fs::canonicalize(mirror) {
+        if path.is_dir() {
+            return Url::from_directory_path(path)
+                .map(|url| url.to_string())
+                .map_err(|_| anyhow!(fl!("mirror-not-found", name = mirror)));
+        }
+    }
+    // anything else than a URL or a path is taken as the name of a mirror
+    if !mirror.contains("://") && !mirror.starts_with('/') {
+        return Err(anyhow!(fl!("mirror-not-found", name = mirror)));
+    }
+    validate_mirror_url(mirror)?;
+
+    Ok(mirror.to_owned())
+}
+
+/// Check that the mirror can be written to the APT sources: an absolute path or an http, https
+/// or file URL, without whitespace or control characters
+pub fn validate_mirror_url(mirror_url: &str) -> Result<()> {
+    let invalid = || anyhow!(fl!("mirror-invalid-url", url = mirror_url));
+    if mirror_url
+        .chars()
+        .any(|c| c.is_whitespace() || c.is_control())
+    {
+        return Err(invalid());
+    }
+    match Url::parse(&network::normalize_mirror_url(mirror_url)) {
+        Ok(url) if url.scheme() == "file" => Ok(()),
+        Ok(url) if matches!(url.scheme(), "http" | "https") && url.has_host() => Ok(()),
+        _ => Err(invalid()),
+    }
+}
+
+/// Check that the mirror may be written to the APT sources on behalf of an unprivileged user:
+/// it must be the default mirror, the pinned mirror, a mirror configured in APT or listed in
+/// `repo.allowed_mirrors`
+pub fn check_allowed_mirror(mirror_url: &str) -> Result<()> {
+    let repo = &config::get().repo;
+    let mirror_url = network::normalize_mirror_url(mirror_url);
+    let allowed = list_mirrors()
+        .unwrap_or_default()
+        .into_values()
+        .chain(get_pinned_mirror())
+        .chain(std::iter::once(repo.default_mirror.clone()))
+        .chain(repo.allowed_mirrors.iter().cloned())
+        .any(|url| is_same_mirror(&network::normalize_mirror_url(&url), &mirror_url));
+    if !allowed {
+        return Err(anyhow!(fl!(
+            "mirror-not-allowed",
+            mirror = mirror_url.as_str()
+        )));
+    }
+
+    Ok(())
+}
+
+/// Returns the last saved ranking (of the user or of the system, whichever is newer),
+/// even if it has expired
+pub fn read_ranking() -> Option<MirrorRanking> {
+    config::get()
+        .cache_files(MIRROR_CACHE_FILE)
+        .into_iter()
+        .filter_map(|path| {
+            serde_json::from_reader::<_, MirrorRanking>(fs::File::open(path).ok()?).ok()
+        })
+        .max_by_key(|ranking| ranking.timestamp)
+}
+
+/// Save the ranking for the next runs, in the cache of the user if not running as root
+pub fn save_ranking(ranking: &MirrorRanking) -> Result<()> {
+    network::write_cache_file(MIRROR_CACHE_FILE, &serde_json::to_vec(ranking)?)
+}
+
+async fn probe_mirror(client: &Client, name: &str, url: &str, deadline: Instant) -> MirrorProbe {
+    let mut probe = MirrorProbe {
+        name: name.to_owned(),
+        url: url.to_owned(),
+        latency: None,
+        throughput: None,
+    };
+    let base = match network::mirror_base(url) {
+        Ok(v) => v,
+        Err(_) => return probe,
+    };
+    if base.scheme() == "file" {
+        // local repositories are as fast as it gets, as long as they are mounted
+        let start = Instant::now();
+        if network::has_local_manifest(&base) {
+            probe.latency = Some(start.elapsed().as_millis() as u64);
+        }
+        return probe;
+    }
+    let test_url = match base.join(&config::get().repo.manifest_path) {
+        Ok(v) => v,
+        Err(_) => return probe,
+    };
+    let mut samples = Vec::with_capacity(PROBE_SAMPLES);
+    for _ in 0..PROBE_SAMPLES {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            break;
+        }
+        let start = Instant::now();
+        // HEAD request works better but some mirrors do not support it correctly
+        let resp = client
+            .get(test_url.clone())
+            .timeout(remaining)
+            .send()
+            .await
+            .and_then(|r| r.error_for_status());
+        let resp = match resp {
+            Ok(resp) => resp,
+            Err(_) => continue,
+        };
+        samples.push(start.elapsed());
+        if probe.throughput.is_some() {
+            // the manifest only needs to be downloaded once, drop the connection
+            continue;
+        }
+        let start = Instant::now();
+        let remaining = deadline.saturating_duration_since(start);
+        if let Ok(Ok(body)) = tokio::time::timeout(remaining, resp.bytes()).await {
+            let elapsed = start.elapsed().as_secs_f64().max(0.001);
+            probe.throughput = Some((body.len() as f64 / elapsed) as u64);
+        }
+    }
+    samples.sort_unstable();
+    probe.latency = samples.get(samples.len() / 2).map(|d| d.as_millis() as u64);
+
+    probe
+}
+
+/// Measure the latency and the throughput of all the mirrors, takes at most `PROBE_DEADLINE`
+pub async fn probe_mirrors(client: &Client, mirrors: &IndexMap<String, String>) -> MirrorRanking {
+    let deadline = Instant::now() + PROBE_DEADLINE;
+    let tasks = mirrors
+        .iter()
+        .map(|(name, url)| probe_mirror(client, name, url, deadline));
+
+    MirrorRanking::new(futures::future::join_all(tasks).await)
+}
+
+async fn get_best_mirror_url_inner(client: &Client) -> Result<String> {
+    if let Some(pinned) = get_pinned_mirror() {
+        return Ok(pinned);
+    }
+    if network::is_offline() {
+        return get_sensible_mirror_url_inner();
+    }
+    let mirrors = list_mirrors()?;
+    if mirrors.len() < 2 {
+        // you don't have many choices here
+        return mirrors.values().next().cloned().ok_or_else(|| anyhow!(""));
+    }
+    let now = time::OffsetDateTime::now_utc().unix_timestamp();
+    let last_ranking = read_ranking();
+    if let Some(ranking) = last_ranking.as_ref().filter(|r| r.is_fresh(&mirrors, now)) {
+        if let Some(url) = ranking.best() {
+            return Ok(url.to_owned());
+        }
+    }
+    let ranking = probe_mirrors(client, &mirrors).await;
+    if let Some(url) = ranking.best() {
+        save_ranking(&ranking).ok();
+        return Ok(url.to_owned());
+    }
+
+    // no mirror responded in time, the last ranking is better than nothing
+    last_ranking
+        .as_ref()
+        .and_then(|r| r.best())
+        .map(|url| url.to_owned())
+        .ok_or_else(|| anyhow!(""))
+}
+
+/// Get the pinned mirror URL, or the fastest mirror according to the (cached) ranking
+pub async fn get_best_mirror_url(client: &Client) -> String {
+    get_best_mirror_url_inner(client)
+        .await
+        .unwrap_or_else(|_| default_mirror_url().to_owned())
+}
+
+fn get_sensible_mirror_url_inner() -> Result<String> {
+    if let Some(pinned) = get_pinned_mirror() {
+        return Ok(pinned);
+    }
+    let now = time::OffsetDateTime::now_utc().unix_timestamp();
+    let mirrors = list_mirrors()?;
+    if let Some(url) = read_ranking()
+        .filter(|r| r.is_fresh(&mirrors, now))
+        .and_then(|r| r.best().map(|url| url.to_owned()))
+    {
+        return Ok(url);
+    }
+
+    mirrors.values().next().cloned().ok_or_else(|| anyhow!(""))
+}
+
+/// Get a sensible mirror URL (async not needed)
+pub fn get_sensible_mirror_url() -> String {
+    get_sensible_mirror_url_inner().unwrap_or_else(|_| default_mirror_url().to_owned())
+}
+
+/// Topic manifest fetched from a mirror that is not behind the primary repository
+#[derive(Debug)]
+pub struct VerifiedTopics {
+    pub topics: TopicManifests,
+    /// Mirror the manifest was fetched from, to be used for the APT sources as well
+    pub mirror_url: String,
+    /// The selected mirror, if it was out of date and replaced with `mirror_url`
+    pub stale_mirror: Option<String>,
+    /// The selected mirror, if it could not be reached and was replaced with `mirror_url`
+    pub unreachable_mirror: Option<String>,
+    /// Fetch time of the manifest, if it was read from the offline cache
+    pub cached: Option<i64>,
+}
+
+#[inline]
+fn is_same_mirror(a: &str, b: &str) -> bool {
+    a.trim_end_matches('/') == b.trim_end_matches('/')
+}
+
+/// Whether `topics` lacks a topic or a topic update present in `reference`
+fn is_behind(topics: &TopicManifests, reference: &TopicManifests) -> bool {
+    reference
+        .iter()
+        .any(|r| !topics.iter().any(|t| t.name == r.name && t.date >= r.date))
+}
+
+#[inline]
+fn newest_topic_date(topics: &TopicManifests) -> i64 {
+    topics.iter().map(|t| t.date).max().unwrap_or(0)
+}
+
+/// Fetch the topic manifest from the mirror and check it against the primary repository
+///
+/// Both manifests are fetched with conditional requests (see `fetch_topics`), the manifest of
+/// the primary repository is only downloaded again when it has changed since the last run.
+/// When the primary repository is unreachable, the manifest is checked against the other
+/// configured mirrors instead. If the mirror is out of date, the freshest manifest is used.
+pub async fn fetch_verified_topics(client: &Client, mirror_url: &str) -> Result<VerifiedTopics> {
+    let topics = fetch_topics(client, mirror_url).await?;
+    let verified = |topics, url: &str, stale: bool| VerifiedTopics {
+        topics,
+        mirror_url: url.to_owned(),
+        stale_mirror: if stale {
+            Some(mirror_url.to_owned())
+        } else {
+            None
+        },
+        unreachable_mirror: None,
+        cached: None,
+    };
+    let primary = default_mirror_url();
+    if is_same_mirror(mirror_url, primary) {
+        return Ok(verified(topics, mirror_url, false));
+    }
+    if let Ok(reference) = fetch_topics(client, primary).await {
+        if is_behind(&topics, &reference) {
+            return Ok(verified(reference, primary, true));
+        }
+        return Ok(verified(topics, mirror_url, false));
+    }
+
+    // the primary repository is unreachable, ask the other mirrors instead
+    let others = list_mirrors()
+        .unwrap_or_default()
+        .into_values()
+        .filter(|url| !is_same_mirror(url, mirror_url))
+        .collect::<Vec<_>>();
+    let tasks = others.iter().map(|url| fetch_topics(client, url));
+    let freshest = futures::future::join_all(tasks)
+        .await
+        .into_iter()
+        .zip(others.iter())
+        .filter_map(|(topics, url)| Some((topics.ok()?, url)))
+        .max_by_key(|(topics, _)| newest_topic_date(topics));
+    match freshest {
+        Some((reference, url)) if is_behind(&topics, &reference) => {
+            Ok(verified(reference, url, true))
+        }
+        _ => Ok(verified(topics, mirror_url, false)),
+    }
+}
+
+/// Returns the mirrors to try when the selected mirror cannot be reached: the primary
+/// repository, then the other mirrors in the order of the last ranking
+fn fallback_mirrors(
+    selected: &str,
+    primary: &str,
+    ranking: Option<&MirrorRanking>,
+    configured: impl IntoIterator<Item = String>,
+) -> Vec<String> {
+    let ranked = ranking
+        .into_iter()
+        .flat_map(|r| r.mirrors.iter().map(|m| m.url.clone()));
+    let mut fallbacks: Vec<String> = Vec::new();
+    for url in std::iter::once(primary.to_owned())
+        .chain(ranked)
+        .chain(configured)
+    {
+        if !is_same_mirror(&url, selected) && !fallbacks.iter().any(|f| is_same_mirror(f, &url)) {
+            fallbacks.push(url);
+        }
+    }
+
+    fallbacks
+}
+
+#[inline]
+fn is_unreachable(error: &anyhow::Error) -> bool {
+    error
+        .downcast_ref::<network::NetworkError>()
+        .is_some_and(|e| e.is_unreachable())
+}
+
+/// Fetch and verify the topic manifest from the mirror, or from the primary repository and the
+/// other mirrors if it cannot be reached
+async fn fetch_reachable_topics(client: &Client, mirror_url: &str) -> Result<VerifiedTopics> {
+    let error = match fetch_verified_topics(client, mirror_url).await {
+        Err(e) if is_unreachable(&e) => e,
+        result => return result,
+    };
+    let ranking = read_ranking();
+    let configured = list_mirrors().unwrap_or_default().into_values();
+    for fallback in fallback_mirrors(
+        mirror_url,
+        default_mirror_url(),
+        ranking.as_ref(),
+        configured,
+    ) {
+        match fetch_verified_topics(client, &fallback).await {
+            Ok(mut fetched) => {
+                fetched.unreachable_mirror = Some(mirror_url.to_owned());
+                return Ok(fetched);
+            }
+            // verification failures must not be hidden by the other mirrors
+            Err(e) if !is_unreachable(&e) => return Err(e),
+            Err(_) => (),
+        }
+    }
+
+    Err(error)
+}
+
+/// Fetch and verify the topic manifest, the cached manifest is used instead in offline mode
+/// or when no mirror can be reached
+pub async fn load_topics(client: &Client, mirror_url: &str) -> Result<VerifiedTopics> {
+    if !network::is_offline() {
+        match fetch_reachable_topics(client, mirror_url).await {
+            Ok(fetched) => {
+                network::save_cached_topics(&fetched.mirror_url, &fetched.topics).ok();
+                return Ok(fetched);
+            }
+            // verification failures must not be hidden by the cache
+            Err(e) if !is_unreachable(&e) => return Err(e),
+            Err(e) => {
+                if network::read_cached_topics().is_err() {
+                    return Err(e);
+                }
+            }
+        }
+    }
+    let cached = network::read_cached_topics()?;
+
+    Ok(VerifiedTopics {
+        topics: cached.topics,
+        mirror_url: cached.mirror,
+        stale_mirror: None,
+        unreachable_mirror: None,
+        cached: Some(cached.fetched),
+    })
+}
+
+#[test]
+fn test_mirror_ranking() {
+    let probe = |name: &str, latency, throughput| MirrorProbe {
+        name: name.to_owned(),
+        url: format!("https://{}.example/", name),
+        latency,
+        throughput,
+    };
+    let ranking = MirrorRanking::new(vec![
+        probe("down", None, None),
+        probe("slow", Some(300), Some(1_000_000)),
+        probe("fast", Some(20), Some(100)),
+        probe("fast2", Some(20), Some(5_000)),
+    ]);
+    let names = ranking
+        .mirrors
+        .iter()
+        .map(|m| m.name.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(names, ["fast2", "fast", "slow", "down"]);
+    assert_eq!(ranking.best(), Some("https://fast2.example/"));
+
+    let mut mirrors = ranking
+        .mirrors
+        .iter()
+        .map(|m| (m.name.clone(), m.url.clone()))
+        .collect::<IndexMap<_, _>>();
+    let now = ranking.timestamp;
+    assert!(ranking.is_fresh(&mirrors, now + 60));
+    assert!(!ranking.is_fresh(&mirrors, now + MIRROR_CACHE_TTL));
+    mirrors.insert("new".to_owned(), "https://new.example/".to_owned());
+    assert!(!ranking.is_fresh(&mirrors, now + 60));
+
+    let unreachable = MirrorRanking::new(vec![probe("down", None, None)]);
+    assert_eq!(unreachable.best(), None);
+
+    let fallbacks = fallback_mirrors(
+        "https://fast2.example",
+        "https://primary.example/",
+        Some(&ranking),
+        vec![
+            "https://other.example/".to_owned(),
+            "https://slow.example/".to_owned(),
+        ],
+    );
+    assert_eq!(
+        fallbacks,
+        [
+            "https://primary.example/",
+            "https://fast.example/",
+            "https://slow.example/",
+            "https://down.example/",
+            "https://other.example/",
+        ]
+    );
+}
+
+#[test]
+fn test_validate_mirror_url() {
+    assert!(validate_mirror_url("https://repo.aosc.io/").is_ok());
+    assert!(validate_mirror_url("http://mirrors.example.com/anthon").is_ok());
+    assert!(validate_mirror_url("file:///mnt/aosc").is_ok());
+    assert!(validate_mirror_url("/mnt/aosc").is_ok());
+    assert!(validate_mirror_url("ftp://mirrors.example.com/anthon").is_err());
+    assert!(validate_mirror_url("mirrors.example.com").is_err());
+    assert!(validate_mirror_url("https://repo.aosc.io/ stable main\ndeb http://x/").is_err());
+    assert!(validate_mirror_url("https://repo.aosc.io/\tdebs").is_err());
+    let ftp = "ftp://mirrors.example.com/anthon";
+    assert_eq!(
+        resolve_mirror(ftp).unwrap_err().to_string(),
+        fl!("mirror-invalid-url", url = ftp)
+    );
+}
+
+#[test]
+fn test_manifest_staleness() {
+    use crate::network::{test_topic, TopicManifest};
+
+    let topic = |name: &str, date| TopicManifest {
+        date,
+        ..test_topic(name)
+    };
+    let primary = vec![topic("kernel-6.6", 20), topic("gnome-46", 10)];
+    assert!(!is_behind(&primary, &primary));
+    // closed topics still listed by the mirror do not matter
+    assert!(!is_behind(
+        &vec![
+            topic("kernel-6.6", 20),
+            topic("gnome-46", 10),
+            topic("old", 1)
+        ],
+        &primary
+    ));
+    // missing topic
+    assert!(is_behind(&vec![topic("kernel-6.6", 20)], &primary));
+    // outdated topic
+    assert!(is_behind(
+        &vec![topic("kernel-6.6", 15), topic("gnome-46", 10)],
+        &primary
+    ));
+    assert_eq!(newest_topic_date(&primary), 20);
+    assert!(is_same_mirror(
+        "https://repo.aosc.io/",
+        default_mirror_url()
+    ));
+}
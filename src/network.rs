@@ -1,15 +1,30 @@
-use std::{collections::HashSet, env::consts::ARCH};
+use std::{
+    collections::{HashMap, HashSet},
+    env::consts::ARCH,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
 
 use anyhow::{anyhow, Result};
-use indexmap::IndexMap;
 use reqwest::Client;
 use serde::{Deserialize, Serialize};
-use tokio::fs::File;
-use tokio::io::AsyncReadExt;
+
+use crate::parser::{insert_newest, list_versions};
+use crate::{config, fl};
 
 const USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
-const PATH_TO_MANIFEST: &str = "debs/manifest/topics.json";
-const APT_GEN_LIST_STATUS: &str = "/var/lib/apt/gen/status.json";
+pub(crate) const PATH_TO_MANIFEST: &str = "debs/manifest/topics.json";
+/// The AOSC archive keyring, the topic manifest is not accepted from other keys by default
+pub(crate) const DEFAULT_KEYRINGS: [&str; 2] = [
+    "/etc/apt/trusted.gpg.d/aosc-archive-keyring.gpg",
+    "/usr/share/keyrings/aosc-archive-keyring.gpg",
+];
+const TOPICS_CACHE_FILE: &str = "topics.json";
+const MANIFEST_CACHE_DIR: &str = "manifests";
+const PACKAGES_CACHE_DIR: &str = "packages";
+/// Largest decompressed topic manifest accepted, against decompression bombs
+const MAX_MANIFEST_SIZE: usize = 64 << 20;
 pub const DEFAULT_REPO_URL: &str = "https://repo.aosc.io";
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -25,12 +40,353 @@ pub struct TopicManifest {
     pub packages: Vec<String>,
 }
 
-#[derive(Deserialize, Debug)]
-struct AptGenListStatus {
-    mirror: IndexMap<String, String>,
+pub(crate) type TopicManifests = Vec<TopicManifest>;
+
+/// The last topic manifest successfully fetched and verified
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CachedTopics {
+    pub fetched: i64,
+    pub mirror: String,
+    pub topics: TopicManifests,
 }
 
-pub(crate) type TopicManifests = Vec<TopicManifest>;
+/// Transfer encodings of the topic manifest, in the order of preference
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+enum ManifestVariant {
+    Xz,
+    Gzip,
+    Plain,
+}
+
+impl ManifestVariant {
+    const ALL: [ManifestVariant; 3] = [
+        ManifestVariant::Xz,
+        ManifestVariant::Gzip,
+        ManifestVariant::Plain,
+    ];
+    const COMPRESSED: [ManifestVariant; 2] = [ManifestVariant::Xz, ManifestVariant::Gzip];
+
+    fn path(self) -> String {
+        let manifest_path = &config::get().repo.manifest_path;
+        match self {
+            ManifestVariant::Xz => format!("{}.xz", manifest_path),
+            ManifestVariant::Gzip => format!("{}.gz", manifest_path),
+            ManifestVariant::Plain => manifest_path.to_owned(),
+        }
+    }
+
+    fn decompress(self, data: &[u8]) -> Result<Vec<u8>> {
+        self.decompress_limited(data, MAX_MANIFEST_SIZE)
+    }
+
+    fn decompress_limited(self, data: &[u8], limit: usize) -> Result<Vec<u8>> {
+        use std::io::Write;
+
+        let mut output = LimitedWriter {
+            output: Vec::with_capacity(data.len().saturating_mul(4).min(limit)),
+            limit,
+        };
+        match self {
+            ManifestVariant::Xz => {
+                lzma_rs::xz_decompress(&mut &data[..], &mut output).map_err(|e| match e {
+                    lzma_rs::error::Error::IoError(e) => anyhow!(e),
+                    e => anyhow!("{:?}", e),
+                })?
+            }
+            ManifestVariant::Gzip => {
+                std::io::copy(&mut flate2::read::GzDecoder::new(data), &mut output)?;
+            }
+            ManifestVariant::Plain => output.write_all(data)?,
+        }
+
+        Ok(output.output)
+    }
+}
+
+/// Buffer that refuses to grow beyond its limit
+struct LimitedWriter {
+    output: Vec<u8>,
+    limit: usize,
+}
+
+impl std::io::Write for LimitedWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.output.len() + buf.len() > self.limit {
+            let limit_mib = self.limit >> 20;
+            return Err(std::io::Error::other(fl!(
+                "manifest-too-large",
+                limit = limit_mib
+            )));
+        }
+        self.output.extend_from_slice(buf);
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Topic manifest of a mirror along with its HTTP validators, for conditional requests
+#[derive(Serialize, Deserialize, Debug)]
+struct ManifestCache {
+    variant: ManifestVariant,
+    etag: Option<String>,
+    last_modified: Option<String>,
+    signed: bool,
+    topics: TopicManifests,
+}
+
+/// Returns the path of the detached signature of the topic manifest
+#[inline]
+fn manifest_signature_path() -> String {
+    format!("{}.sig", config::get().repo.manifest_path)
+}
+
+/// Returns the URL of the mirror used when no mirror is configured
+#[inline]
+pub fn default_mirror_url() -> &'static str {
+    &config::get().repo.default_mirror
+}
+
+/// Returns the name of the manifest cache of the mirror, relative to the cache directory
+fn manifest_cache_name(mirror_url: &str) -> PathBuf {
+    use sha2::Digest;
+
+    let hash = sha2::Sha256::digest(mirror_url.trim_end_matches('/').as_bytes());
+    Path::new(MANIFEST_CACHE_DIR).join(format!("{:x}.json", hash))
+}
+
+fn read_manifest_cache(mirror_url: &str) -> Option<ManifestCache> {
+    config::get()
+        .cache_files(manifest_cache_name(mirror_url))
+        .into_iter()
+        .find_map(|path| {
+            let f = std::fs::File::open(path).ok()?;
+            serde_json::from_reader(std::io::BufReader::new(f)).ok()
+        })
+}
+
+/// Save the manifest cache of the mirror, in the cache of the user if not running as root
+fn save_manifest_cache(mirror_url: &str, cache: &ManifestCache) -> Result<()> {
+    write_cache_file(manifest_cache_name(mirror_url), &serde_json::to_vec(cache)?)
+}
+
+/// Classified network failures, displayed as localized messages
+#[derive(Debug)]
+pub enum NetworkError {
+    /// The host name of the mirror could not be resolved
+    Dns { url: String },
+    /// The TLS handshake failed, e.g. because of an untrusted certificate
+    Tls { url: String, error: String },
+    /// The connection was refused or reset
+    Connect { url: String, error: String },
+    /// The mirror did not respond in time
+    Timeout { url: String },
+    /// The mirror returned an error status
+    Status { url: String, status: u16 },
+    /// The response could not be decoded
+    Decode { url: String, error: String },
+}
+
+impl NetworkError {
+    /// Whether the mirror could not be reached, as opposed to returning bad data
+    pub fn is_unreachable(&self) -> bool {
+        match self {
+            NetworkError::Status { status, .. } => *status >= 500,
+            NetworkError::Decode { .. } => false,
+            _ => true,
+        }
+    }
+
+    fn decode(url: &reqwest::Url, error: impl std::fmt::Display) -> Self {
+        NetworkError::Decode {
+            url: url.to_string(),
+            error: error.to_string(),
+        }
+    }
+}
+
+impl From<reqwest::Error> for NetworkError {
+    fn from(e: reqwest::Error) -> Self {
+        use std::error::Error;
+
+        let url = e.url().map(|u| u.to_string()).unwrap_or_default();
+        if let Some(status) = e.status() {
+            return NetworkError::Status {
+                url,
+                status: status.as_u16(),
+            };
+        }
+        if e.is_timeout() {
+            return NetworkError::Timeout { url };
+        }
+        if e.is_decode() || e.is_body() {
+            return NetworkError::Decode {
+                url,
+                error: e.to_string(),
+            };
+        }
+        let error = innermost_error(&e);
+        match e.source() {
+            Some(source) if e.is_connect() => NetworkError::from_connect_error(url, error, source),
+            _ => NetworkError::Connect { url, error },
+        }
+    }
+}
+
+impl NetworkError {
+    /// Classify a connection failure by the types of the errors in its chain
+    fn from_connect_error(
+        url: String,
+        error: String,
+        source: &(dyn std::error::Error + 'static),
+    ) -> Self {
+        let mut cause = Some(source);
+        while let Some(current) = cause {
+            if current.is::<ResolveError>() {
+                return NetworkError::Dns { url };
+            }
+            if current.is::<native_tls::Error>() {
+                return NetworkError::Tls { url, error };
+            }
+            if current
+                .downcast_ref::<std::io::Error>()
+                .is_some_and(|e| e.kind() == std::io::ErrorKind::TimedOut)
+            {
+                return NetworkError::Timeout { url };
+            }
+            cause = current.source();
+        }
+
+        NetworkError::Connect { url, error }
+    }
+}
+
+/// Whether the error was caused by a failure to resolve the host name
+fn is_resolve_error(e: &(dyn std::error::Error + 'static)) -> bool {
+    let mut cause = Some(e);
+    while let Some(current) = cause {
+        if current.is::<ResolveError>() {
+            return true;
+        }
+        cause = current.source();
+    }
+
+    false
+}
+
+/// Returns the message of the innermost cause of the error
+fn innermost_error(e: &(dyn std::error::Error + 'static)) -> String {
+    let mut innermost = e;
+    while let Some(cause) = innermost.source() {
+        innermost = cause;
+    }
+
+    innermost.to_string()
+}
+
+impl std::fmt::Display for NetworkError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let message = match self {
+            NetworkError::Dns { url } => fl!("net-error-dns", url = url.as_str()),
+            NetworkError::Tls { url, error } => {
+                fl!("net-error-tls", url = url.as_str(), error = error.as_str())
+            }
+            NetworkError::Connect { url, error } => {
+                fl!(
+                    "net-error-connect",
+                    url = url.as_str(),
+                    error = error.as_str()
+                )
+            }
+            NetworkError::Timeout { url } => fl!("net-error-timeout", url = url.as_str()),
+            NetworkError::Status { url, status } => {
+                fl!("net-error-http", url = url.as_str(), status = status)
+            }
+            NetworkError::Decode { url, error } => {
+                fl!(
+                    "net-error-decode",
+                    url = url.as_str(),
+                    error = error.as_str()
+                )
+            }
+        };
+
+        f.write_str(&message)
+    }
+}
+
+impl std::error::Error for NetworkError {}
+
+/// Failure to resolve the host name of a mirror
+#[derive(Debug)]
+struct ResolveError(std::io::Error);
+
+impl std::fmt::Display for ResolveError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl std::error::Error for ResolveError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.0)
+    }
+}
+
+/// The system resolver, its failures are reported as `ResolveError` to tell them apart from
+/// the other connection failures
+struct SystemResolver;
+
+impl reqwest::dns::Resolve for SystemResolver {
+    fn resolve(&self, name: reqwest::dns::Name) -> reqwest::dns::Resolving {
+        Box::pin(async move {
+            let addrs = tokio::net::lookup_host(format!("{}:0", name.as_str()))
+                .await
+                .map_err(|e| {
+                    Box::new(ResolveError(e)) as Box<dyn std::error::Error + Send + Sync>
+                })?;
+
+            Ok(Box::new(addrs) as reqwest::dns::Addrs)
+        })
+    }
+}
+
+/// Whether topic manifests without a signature are accepted
+static ALLOW_UNSIGNED: AtomicBool = AtomicBool::new(false);
+
+/// Accept topic manifests without a signature (manifests with an invalid signature are
+/// always refused)
+pub fn set_allow_unsigned(allow: bool) {
+    ALLOW_UNSIGNED.store(allow, Ordering::Relaxed);
+}
+
+/// Whether the cached topic manifest is used instead of the network
+static OFFLINE: AtomicBool = AtomicBool::new(false);
+
+pub fn set_offline(offline: bool) {
+    OFFLINE.store(offline, Ordering::Relaxed);
+}
+
+#[inline]
+pub fn is_offline() -> bool {
+    OFFLINE.load(Ordering::Relaxed)
+}
+
+/// Whether the caches are left untouched, dry runs must not write anything
+static NO_WRITE: AtomicBool = AtomicBool::new(false);
+
+pub fn set_no_write(no_write: bool) {
+    NO_WRITE.store(no_write, Ordering::Relaxed);
+}
+
+#[inline]
+pub fn is_no_write() -> bool {
+    NO_WRITE.load(Ordering::Relaxed)
+}
 
 #[inline]
 pub(crate) fn get_arch_name() -> Option<&'static str> {
@@ -46,83 +402,526 @@ pub(crate) fn get_arch_name() -> Option<&'static str> {
     }
 }
 
-pub fn create_http_client() -> Result<Client, reqwest::Error> {
-    Client::builder().user_agent(USER_AGENT).build()
+/// Create the HTTP client with the proxy, CA certificates and timeouts from the configuration
+pub fn create_http_client() -> Result<Client> {
+    let config = &config::get().network;
+    let mut builder = Client::builder()
+        .user_agent(USER_AGENT)
+        .timeout(Duration::from_secs(config.timeout))
+        .connect_timeout(Duration::from_secs(config.connect_timeout))
+        .read_timeout(Duration::from_secs(config.read_timeout))
+        .dns_resolver(std::sync::Arc::new(SystemResolver));
+    if let Some(proxy) = &config.proxy {
+        let proxy = reqwest::Proxy::all(proxy)?.no_proxy(
+            config
+                .no_proxy
+                .as_deref()
+                .and_then(reqwest::NoProxy::from_string),
+        );
+        builder = builder.proxy(proxy);
+    }
+    if let Some(ca_bundle) = &config.ca_bundle {
+        let pem = std::fs::read(ca_bundle)
+            .map_err(|e| anyhow!("Failed to read {}: {}", ca_bundle.display(), e))?;
+        for cert in reqwest::Certificate::from_pem_bundle(&pem)? {
+            builder = builder.add_root_certificate(cert);
+        }
+    }
+
+    Ok(builder.build()?)
 }
 
-async fn test_mirror<'a>(
-    client: &Client,
-    url: reqwest::Url,
-    mirror: &'a str,
-) -> Result<&'a str, reqwest::Error> {
-    // HEAD request works better but some mirrors do not support it correctly
-    client
-        .get(url)
-        .send()
-        .await?
-        .error_for_status()
-        .map(|_| mirror)
-}
-
-async fn get_best_mirror_url_inner(client: &Client) -> Result<String> {
-    let mut file = File::open(APT_GEN_LIST_STATUS).await?;
-    let mut buffer = Vec::with_capacity(1024);
-    file.read_to_end(&mut buffer).await?;
-    let mirrors: AptGenListStatus = serde_json::from_slice(&buffer)?;
-    if mirrors.mirror.len() < 2 {
-        // you don't have many choices here
-        return Err(anyhow!(""));
-    }
-    let mut tasks = Vec::with_capacity(mirrors.mirror.len());
-    for mirror in mirrors.mirror.iter() {
-        let test_url = match reqwest::Url::parse(mirror.1) {
-            Ok(v) => v.join(PATH_TO_MANIFEST).unwrap(),
-            Err(_) => continue,
+/// Whether the response status is worth retrying the request
+#[inline]
+fn is_transient_status(status: reqwest::StatusCode) -> bool {
+    status.is_server_error()
+        || status == reqwest::StatusCode::REQUEST_TIMEOUT
+        || status == reqwest::StatusCode::TOO_MANY_REQUESTS
+}
+
+/// Send the request, retrying transient failures with an exponential backoff
+async fn send_with_retry(
+    request: reqwest::RequestBuilder,
+) -> Result<reqwest::Response, NetworkError> {
+    let config = &config::get().network;
+    let mut delay = Duration::from_millis(config.retry_delay);
+    let mut retries = config.retries;
+    loop {
+        // requests with a streaming body cannot be cloned, they are sent only once
+        let attempt = match request.try_clone() {
+            Some(attempt) => attempt,
+            None => return Ok(request.send().await?),
+        };
+        let transient = match attempt.send().await {
+            Ok(resp) if is_transient_status(resp.status()) => Ok(resp),
+            Ok(resp) => return Ok(resp),
+            // a host name which cannot be resolved will not be resolved on the next attempt
+            Err(e) if e.is_connect() && is_resolve_error(&e) => return Err(e.into()),
+            Err(e) if e.is_connect() || e.is_timeout() => Err(e),
+            Err(e) => return Err(e.into()),
         };
-        tasks.push(Box::pin(test_mirror(client, test_url, mirror.1)));
+        if retries == 0 {
+            return match transient {
+                Ok(resp) => Ok(resp),
+                Err(e) => Err(e.into()),
+            };
+        }
+        tokio::time::sleep(delay).await;
+        delay *= 2;
+        retries -= 1;
     }
-    if tasks.is_empty() {
-        // well, that sad, none of the mirrors have a correct URL
-        return Err(anyhow!(""));
+}
+
+/// Check the status of the response and read its body
+async fn read_response(resp: reqwest::Response) -> Result<Vec<u8>, NetworkError> {
+    let resp = resp.error_for_status()?;
+
+    Ok(resp.bytes().await?.to_vec())
+}
+
+/// Returns the configured keyrings, directories are replaced with the `.gpg` and `.asc` keyrings
+/// they contain
+fn find_keyrings(configured: &[PathBuf]) -> Vec<PathBuf> {
+    let mut keyrings = Vec::new();
+    for path in configured {
+        if path.is_file() {
+            keyrings.push(path.to_owned());
+            continue;
+        }
+        if let Ok(entries) = std::fs::read_dir(path) {
+            let mut found = entries
+                .filter_map(|e| e.ok().map(|e| e.path()))
+                .filter(|p| p.is_file() && p.extension().is_some_and(|e| e == "gpg" || e == "asc"))
+                .collect::<Vec<_>>();
+            found.sort();
+            keyrings.extend(found);
+        }
+    }
+
+    keyrings
+}
+
+/// Convert an ASCII-armored keyring to the binary format, the only one gpgv understands
+fn dearmor(armored: &str) -> Result<Vec<u8>> {
+    use base64::Engine;
+
+    let mut keyring = Vec::new();
+    let mut body = String::new();
+    let (mut in_block, mut in_headers) = (false, false);
+    for line in armored.lines().map(|l| l.trim()) {
+        if line.starts_with("-----BEGIN PGP PUBLIC KEY BLOCK") {
+            (in_block, in_headers) = (true, true);
+        } else if !in_block {
+            continue;
+        } else if line.starts_with("-----END PGP PUBLIC KEY BLOCK") {
+            keyring.extend(base64::engine::general_purpose::STANDARD.decode(&body)?);
+            body.clear();
+            in_block = false;
+        } else if in_headers && line.contains(": ") {
+            // armor headers (e.g. `Comment: ...`)
+        } else if line.starts_with('=') {
+            // checksum
+        } else {
+            in_headers = false;
+            body.push_str(line);
+        }
+    }
+    if keyring.is_empty() {
+        return Err(anyhow!("no public key block found"));
+    }
+
+    Ok(keyring)
+}
+
+/// Verify the detached signature of the manifest against the configured keyrings
+async fn verify_manifest(manifest: &[u8], signature: &[u8]) -> Result<()> {
+    use std::io::Write;
+    use tempfile::NamedTempFile;
+
+    let config = config::get();
+    let configured = config
+        .repo
+        .keyrings
+        .iter()
+        .map(|k| config.system_path(k))
+        .collect::<Vec<_>>();
+    let keyrings = find_keyrings(&configured);
+    if keyrings.is_empty() {
+        return Err(anyhow!(fl!("manifest-no-keyring")));
+    }
+    // armored keyrings are converted to temporary binary keyrings
+    let mut dearmored = Vec::new();
+    let mut keyring_paths = Vec::with_capacity(keyrings.len());
+    for keyring in keyrings {
+        let content = std::fs::read(&keyring)?;
+        if !content.starts_with(b"-----BEGIN PGP") {
+            keyring_paths.push(keyring);
+            continue;
+        }
+        let binary = dearmor(&String::from_utf8_lossy(&content))
+            .map_err(|e| anyhow!("Failed to read {}: {}", keyring.display(), e))?;
+        let mut f = NamedTempFile::new()?;
+        f.write_all(&binary)?;
+        keyring_paths.push(f.path().to_owned());
+        dearmored.push(f);
+    }
+    let mut manifest_file = NamedTempFile::new()?;
+    manifest_file.write_all(manifest)?;
+    let mut signature_file = NamedTempFile::new()?;
+    signature_file.write_all(signature)?;
+
+    let mut cmd = tokio::process::Command::new("gpgv");
+    for keyring in keyring_paths.iter() {
+        cmd.arg("--keyring").arg(keyring);
+    }
+    let output = cmd
+        .arg(signature_file.path())
+        .arg(manifest_file.path())
+        .output()
+        .await?;
+    if !output.status.success() {
+        let error = String::from_utf8_lossy(&output.stderr);
+        return Err(anyhow!(fl!("manifest-bad-signature", error = error.trim())));
+    }
+
+    Ok(())
+}
+
+#[inline]
+fn header_value(resp: &reqwest::Response, name: reqwest::header::HeaderName) -> Option<String> {
+    resp.headers()
+        .get(name)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.to_owned())
+}
+
+/// Verify the signature of the manifest if there is one, returns whether the manifest is signed
+async fn check_signature(
+    mirror_url: &str,
+    manifest: &[u8],
+    signature: Option<&[u8]>,
+    allow_unsigned: bool,
+) -> Result<bool> {
+    match signature {
+        Some(signature) => {
+            verify_manifest(manifest, signature).await?;
+            Ok(true)
+        }
+        None if allow_unsigned => Ok(false),
+        None => Err(anyhow!(fl!("manifest-unsigned", mirror = mirror_url))),
+    }
+}
+
+/// Fetch the signature of the manifest, returns `None` if the manifest is not signed
+async fn fetch_signature(client: &Client, base: &reqwest::Url) -> Result<Option<Vec<u8>>> {
+    let resp = send_with_retry(client.get(base.join(&manifest_signature_path())?)).await?;
+    if resp.status() == reqwest::StatusCode::NOT_FOUND {
+        return Ok(None);
     }
-    // start the test
-    let result = futures::future::select_ok(tasks).await?;
 
-    Ok(result.0.to_owned())
+    Ok(Some(read_response(resp).await?))
 }
 
-pub async fn get_best_mirror_url(client: &Client) -> String {
-    get_best_mirror_url_inner(client)
-        .await
-        .unwrap_or_else(|_| DEFAULT_REPO_URL.to_owned())
+/// Convert a local path to a `file://` URL, other mirror URLs are returned as is
+pub fn normalize_mirror_url(mirror_url: &str) -> String {
+    if mirror_url.starts_with('/') {
+        if let Ok(url) = reqwest::Url::from_directory_path(mirror_url) {
+            return url.to_string();
+        }
+    }
+
+    mirror_url.to_owned()
 }
 
-fn get_sensible_mirror_url_inner() -> Result<String> {
-    let mirrors: AptGenListStatus =
-        serde_json::from_reader(std::fs::File::open(APT_GEN_LIST_STATUS)?)?;
-    mirrors
-        .mirror
-        .first()
-        .map(|v| v.1.to_owned())
-        .ok_or_else(|| anyhow!(""))
+/// Returns the base URL of the mirror, the paths of the repository are relative to it
+pub(crate) fn mirror_base(mirror_url: &str) -> Result<reqwest::Url> {
+    let mut url = normalize_mirror_url(mirror_url);
+    if !url.ends_with('/') {
+        url.push('/');
+    }
+
+    Ok(reqwest::Url::parse(&url)?)
 }
 
-/// Get a sensible mirror URL (async not needed)
-pub fn get_sensible_mirror_url() -> String {
-    get_sensible_mirror_url_inner().unwrap_or_else(|_| DEFAULT_REPO_URL.to_owned())
+/// Read a file from a local repository, returns `None` if it does not exist
+fn read_local(base: &reqwest::Url, path: &str) -> Result<Option<Vec<u8>>> {
+    let path = base
+        .join(path)?
+        .to_file_path()
+        .map_err(|_| anyhow!("invalid local path: {}", base))?;
+    match std::fs::read(path) {
+        Ok(data) => Ok(Some(data)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Whether the local repository contains a topic manifest
+pub(crate) fn has_local_manifest(base: &reqwest::Url) -> bool {
+    ManifestVariant::ALL.iter().any(|v| {
+        base.join(&v.path())
+            .ok()
+            .and_then(|u| u.to_file_path().ok())
+            .is_some_and(|p| p.is_file())
+    })
+}
+
+async fn fetch_local_topics(
+    base: &reqwest::Url,
+    mirror_url: &str,
+    allow_unsigned: bool,
+) -> Result<TopicManifests> {
+    for variant in ManifestVariant::ALL {
+        if let Some(data) = read_local(base, &variant.path())? {
+            let manifest = variant.decompress(&data)?;
+            let signature = read_local(base, &manifest_signature_path())?;
+            check_signature(mirror_url, &manifest, signature.as_deref(), allow_unsigned).await?;
+
+            return Ok(
+                serde_json::from_slice(&manifest).map_err(|e| NetworkError::decode(base, e))?
+            );
+        }
+    }
+
+    Err(anyhow!(fl!(
+        "mirror-local-no-manifest",
+        mirror = mirror_url
+    )))
 }
 
+/// Fetch the topic manifest and verify its signature
+///
+/// The compressed variants of the manifest are preferred, and the manifest is not downloaded
+/// again if it has not changed since the last fetch from this mirror.
 pub async fn fetch_topics(client: &Client, mirror_url: &str) -> Result<TopicManifests> {
-    let url = reqwest::Url::parse(mirror_url)?.join(PATH_TO_MANIFEST)?;
-    let resp = client.get(url).send().await?.error_for_status()?;
-    let topics: TopicManifests = resp.json().await?;
+    fetch_topics_with_policy(client, mirror_url, ALLOW_UNSIGNED.load(Ordering::Relaxed)).await
+}
 
-    Ok(topics)
+/// Fetch the topic manifest, accepting unsigned manifests only if `allow_unsigned` is set
+async fn fetch_topics_with_policy(
+    client: &Client,
+    mirror_url: &str,
+    allow_unsigned: bool,
+) -> Result<TopicManifests> {
+    let base = mirror_base(mirror_url)?;
+    if base.scheme() == "file" {
+        return fetch_local_topics(&base, mirror_url, allow_unsigned).await;
+    }
+    let cache = read_manifest_cache(mirror_url);
+    let mut variants = ManifestVariant::COMPRESSED.to_vec();
+    if let Some(cache) = cache
+        .as_ref()
+        .filter(|c| c.variant != ManifestVariant::Plain)
+    {
+        // try the variant found last time first
+        variants.retain(|v| *v != cache.variant);
+        variants.insert(0, cache.variant);
+    }
+    for variant in variants {
+        if let Some(topics) = fetch_manifest_variant(
+            client,
+            &base,
+            mirror_url,
+            variant,
+            cache.as_ref(),
+            allow_unsigned,
+        )
+        .await?
+        {
+            return Ok(topics);
+        }
+    }
+    // the plain manifest is the final fallback, which must exist
+    fetch_manifest_variant(
+        client,
+        &base,
+        mirror_url,
+        ManifestVariant::Plain,
+        cache.as_ref(),
+        allow_unsigned,
+    )
+    .await?
+    .ok_or_else(|| {
+        NetworkError::Status {
+            url: base
+                .join(&ManifestVariant::Plain.path())
+                .map(|u| u.to_string())
+                .unwrap_or_default(),
+            status: reqwest::StatusCode::NOT_FOUND.as_u16(),
+        }
+        .into()
+    })
+}
+
+/// Fetch one variant of the topic manifest, returns `None` if the mirror does not provide it
+async fn fetch_manifest_variant(
+    client: &Client,
+    base: &reqwest::Url,
+    mirror_url: &str,
+    variant: ManifestVariant,
+    cache: Option<&ManifestCache>,
+    allow_unsigned: bool,
+) -> Result<Option<TopicManifests>> {
+    use reqwest::header::{ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED};
+
+    let url = base.join(&variant.path())?;
+    let mut request = client.get(url.clone());
+    let cache = cache.filter(|c| c.variant == variant);
+    if let Some(cache) = cache {
+        if let Some(etag) = &cache.etag {
+            request = request.header(IF_NONE_MATCH, etag);
+        }
+        if let Some(last_modified) = &cache.last_modified {
+            request = request.header(IF_MODIFIED_SINCE, last_modified);
+        }
+    }
+    let resp = send_with_retry(request).await?;
+    match (resp.status(), cache) {
+        (reqwest::StatusCode::NOT_MODIFIED, Some(cache)) => {
+            if !cache.signed && !allow_unsigned {
+                return Err(anyhow!(fl!("manifest-unsigned", mirror = mirror_url)));
+            }
+            return Ok(Some(cache.topics.clone()));
+        }
+        (reqwest::StatusCode::NOT_FOUND, _) => return Ok(None),
+        _ => (),
+    }
+    let etag = header_value(&resp, ETAG);
+    let last_modified = header_value(&resp, LAST_MODIFIED);
+    let manifest = variant
+        .decompress(&read_response(resp).await?)
+        .map_err(|e| NetworkError::decode(&url, e))?;
+    let signature = fetch_signature(client, base).await?;
+    let signed =
+        check_signature(mirror_url, &manifest, signature.as_deref(), allow_unsigned).await?;
+    let topics: TopicManifests =
+        serde_json::from_slice(&manifest).map_err(|e| NetworkError::decode(&url, e))?;
+    let cache = ManifestCache {
+        variant,
+        etag,
+        last_modified,
+        signed,
+        topics,
+    };
+    save_manifest_cache(mirror_url, &cache).ok();
+
+    Ok(Some(cache.topics))
+}
+
+/// Write the cache file, in the cache of the user if not running as root
+/// (nothing is written in dry runs)
+pub(crate) fn write_cache_file<P: AsRef<Path>>(name: P, content: &[u8]) -> Result<()> {
+    if is_no_write() {
+        return Ok(());
+    }
+    let path = config::get().cache_file(name);
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(path, content)?;
+
+    Ok(())
+}
+
+/// Save the verified topic manifest for offline use
+pub fn save_cached_topics(mirror_url: &str, topics: &[TopicManifest]) -> Result<()> {
+    let cached = CachedTopics {
+        fetched: time::OffsetDateTime::now_utc().unix_timestamp(),
+        mirror: mirror_url.to_owned(),
+        topics: topics.to_vec(),
+    };
+
+    write_cache_file(TOPICS_CACHE_FILE, &serde_json::to_vec(&cached)?)
+}
+
+/// Returns the last topic manifest saved for offline use (by the user or by root)
+pub fn read_cached_topics() -> Result<CachedTopics> {
+    config::get()
+        .cache_files(TOPICS_CACHE_FILE)
+        .into_iter()
+        .filter_map(|path| {
+            let f = std::fs::File::open(path).ok()?;
+            serde_json::from_reader::<_, CachedTopics>(std::io::BufReader::new(f)).ok()
+        })
+        .max_by_key(|cached| cached.fetched)
+        .ok_or_else(|| anyhow!(fl!("offline-no-cache")))
+}
+
+/// Returns the name of the cached package versions of the topic, relative to the cache directory
+fn packages_cache_name(mirror_url: &str, topic: &str) -> PathBuf {
+    use sha2::Digest;
+
+    let key = format!("{}\n{}", mirror_url.trim_end_matches('/'), topic);
+    let hash = sha2::Sha256::digest(key.as_bytes());
+    Path::new(PACKAGES_CACHE_DIR).join(format!("{:x}.json", hash))
+}
+
+fn read_cached_packages(mirror_url: &str, topic: &str) -> Option<HashMap<String, String>> {
+    config::get()
+        .cache_files(packages_cache_name(mirror_url, topic))
+        .into_iter()
+        .find_map(|path| {
+            let f = std::fs::File::open(path).ok()?;
+            serde_json::from_reader(std::io::BufReader::new(f)).ok()
+        })
+}
+
+/// Fetch the package versions provided by the topic, returns a map of package names to versions
+pub async fn fetch_topic_packages(
+    client: &Client,
+    mirror_url: &str,
+    topic: &str,
+) -> Result<HashMap<String, String>> {
+    let arch = get_arch_name().ok_or_else(|| anyhow!("unknown architecture"))?;
+    let base = mirror_base(mirror_url)?;
+    // local repositories are available offline
+    if is_offline() && base.scheme() != "file" {
+        return read_cached_packages(mirror_url, topic)
+            .ok_or_else(|| anyhow!(fl!("offline-no-packages", topic = topic)));
+    }
+    let mut versions = HashMap::new();
+    let indices = config::get().repo.components.iter().flat_map(|component| {
+        IntoIterator::into_iter([arch, "all"]).map(move |arch| {
+            format!(
+                "debs/dists/{}/{}/binary-{}/Packages",
+                topic, component, arch
+            )
+        })
+    });
+    for path in indices {
+        let index = if base.scheme() == "file" {
+            read_local(&base, &path)?
+        } else {
+            let resp = send_with_retry(client.get(base.join(&path)?)).await?;
+            if resp.status() == reqwest::StatusCode::NOT_FOUND {
+                None
+            } else {
+                Some(read_response(resp).await?)
+            }
+        };
+        // not all the topics contain architecture-independent packages
+        if let Some(index) = index {
+            let versions_in_index = list_versions(&mut index.as_slice(), false)
+                .map_err(|_| anyhow!("Failed to parse the package index {}", path))?;
+            for (package, version) in versions_in_index {
+                insert_newest(&mut versions, package, version);
+            }
+        }
+    }
+
+    if base.scheme() != "file" {
+        write_cache_file(
+            packages_cache_name(mirror_url, topic),
+            &serde_json::to_vec(&versions)?,
+        )
+        .ok();
+    }
+
+    Ok(versions)
 }
 
 pub fn filter_topics(topics: TopicManifests) -> Result<TopicManifests> {
-    let mut filtered: TopicManifests = Vec::new();
-    filtered.reserve(topics.len());
+    let mut filtered: TopicManifests = Vec::with_capacity(topics.len());
     let arch = get_arch_name().ok_or_else(|| anyhow!("unknown architecture"))?;
 
     for topic in topics {
@@ -134,6 +933,202 @@ pub fn filter_topics(topics: TopicManifests) -> Result<TopicManifests> {
     Ok(filtered)
 }
 
+/// Returns a topic that is not enrolled, has no architectures and no packages
+#[cfg(test)]
+pub(crate) fn test_topic(name: &str) -> TopicManifest {
+    TopicManifest {
+        enabled: false,
+        closed: false,
+        name: name.to_owned(),
+        description: None,
+        date: 0,
+        arch: HashSet::new(),
+        packages: vec![],
+    }
+}
+
+#[test]
+fn test_manifest_variants() {
+    use std::io::Write;
+
+    let manifest = br#"[{"name":"kernel-6.6","date":0,"arch":["all"],"packages":[]}]"#;
+    let mut gzip = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    gzip.write_all(manifest).unwrap();
+    let gzip = gzip.finish().unwrap();
+    let mut xz = Vec::new();
+    lzma_rs::xz_compress(&mut &manifest[..], &mut xz).unwrap();
+
+    assert_eq!(ManifestVariant::Gzip.decompress(&gzip).unwrap(), manifest);
+    assert_eq!(ManifestVariant::Xz.decompress(&xz).unwrap(), manifest);
+    assert_eq!(
+        ManifestVariant::Plain.decompress(manifest).unwrap(),
+        manifest
+    );
+    assert!(ManifestVariant::Xz.decompress(&gzip).is_err());
+    assert!(ManifestVariant::Gzip
+        .decompress_limited(&gzip, manifest.len() - 1)
+        .is_err());
+    assert!(ManifestVariant::Xz
+        .decompress_limited(&xz, manifest.len() - 1)
+        .is_err());
+    assert!(ManifestVariant::Plain
+        .decompress_limited(manifest, manifest.len() - 1)
+        .is_err());
+    assert_eq!(ManifestVariant::Gzip.path(), "debs/manifest/topics.json.gz");
+    assert_eq!(
+        manifest_cache_name("https://repo.aosc.io/"),
+        manifest_cache_name(DEFAULT_REPO_URL)
+    );
+}
+
+#[test]
+fn test_local_mirror() {
+    use std::io::Write;
+
+    let dir = tempfile::tempdir().unwrap();
+    let manifest_dir = dir.path().join("debs/manifest");
+    std::fs::create_dir_all(&manifest_dir).unwrap();
+    let mut gzip = flate2::write::GzEncoder::new(
+        std::fs::File::create(manifest_dir.join("topics.json.gz")).unwrap(),
+        flate2::Compression::default(),
+    );
+    gzip.write_all(
+        br#"[{"name":"kernel-6.6","date":0,"arch":["all"],"packages":["linux-kernel"]}]"#,
+    )
+    .unwrap();
+    gzip.finish().unwrap();
+    let index_dir = dir.path().join("debs/dists/kernel-6.6/main/binary-all");
+    std::fs::create_dir_all(&index_dir).unwrap();
+    std::fs::write(
+        index_dir.join("Packages"),
+        "Package: linux-kernel\nVersion: 6.6.30-0\n",
+    )
+    .unwrap();
+
+    let mirror = dir.path().to_str().unwrap();
+    let base = mirror_base(mirror).unwrap();
+    assert_eq!(base.scheme(), "file");
+    assert!(has_local_manifest(&base));
+
+    let runner = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    let client = create_http_client().unwrap();
+    let error = runner
+        .block_on(fetch_topics_with_policy(&client, mirror, false))
+        .unwrap_err();
+    assert!(error.to_string().contains(mirror));
+    let topics = runner
+        .block_on(fetch_topics_with_policy(&client, mirror, true))
+        .unwrap();
+    assert_eq!(topics.len(), 1);
+    assert_eq!(topics[0].name, "kernel-6.6");
+    let versions = runner
+        .block_on(fetch_topic_packages(&client, base.as_str(), "kernel-6.6"))
+        .unwrap();
+    assert_eq!(versions["linux-kernel"], "6.6.30-0");
+}
+
+#[test]
+fn test_network_errors() {
+    let runner = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let _runtime = runner.enter();
+    let client = create_http_client().unwrap();
+    // nothing listens on the port any more
+    let port = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let error = runner
+        .block_on(client.get(format!("http://127.0.0.1:{}/", port)).send())
+        .unwrap_err();
+    let error = NetworkError::from(error);
+    assert!(matches!(error, NetworkError::Connect { .. }));
+    assert!(error.is_unreachable());
+    // a server which does not speak TLS
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let server = std::thread::spawn(move || {
+        use std::io::Write;
+
+        let (mut stream, _) = listener.accept().unwrap();
+        stream.write_all(b"HTTP/1.1 400 Bad Request\r\n\r\n").ok();
+    });
+    let error = runner
+        .block_on(client.get(format!("https://127.0.0.1:{}/", port)).send())
+        .unwrap_err();
+    server.join().unwrap();
+    assert!(matches!(
+        NetworkError::from(error),
+        NetworkError::Tls { .. }
+    ));
+
+    let resolve = ResolveError(std::io::Error::other("no address"));
+    assert!(is_resolve_error(&resolve));
+    assert!(matches!(
+        NetworkError::from_connect_error(String::new(), String::new(), &resolve),
+        NetworkError::Dns { .. }
+    ));
+    let timeout = std::io::Error::from(std::io::ErrorKind::TimedOut);
+    assert!(!is_resolve_error(&timeout));
+    assert!(matches!(
+        NetworkError::from_connect_error(String::new(), String::new(), &timeout),
+        NetworkError::Timeout { .. }
+    ));
+
+    let url = reqwest::Url::parse(DEFAULT_REPO_URL).unwrap();
+    let error = NetworkError::decode(&url, serde_json::from_str::<u8>("{").unwrap_err());
+    assert!(!error.is_unreachable());
+    assert!(!NetworkError::Status {
+        url: DEFAULT_REPO_URL.to_owned(),
+        status: 404
+    }
+    .is_unreachable());
+    assert!(is_transient_status(reqwest::StatusCode::BAD_GATEWAY));
+    assert!(!is_transient_status(reqwest::StatusCode::NOT_FOUND));
+}
+
+#[test]
+fn test_find_keyrings() {
+    let dir = tempfile::tempdir().unwrap();
+    let keyring = dir.path().join("aosc-archive-keyring.gpg");
+    let keyring_dir = dir.path().join("trusted.gpg.d");
+    std::fs::create_dir(&keyring_dir).unwrap();
+    for name in ["b.gpg", "a.gpg", "c.asc", "README"].iter() {
+        std::fs::write(keyring_dir.join(name), b"").unwrap();
+    }
+    assert_eq!(
+        find_keyrings(std::slice::from_ref(&keyring_dir)),
+        vec![
+            keyring_dir.join("a.gpg"),
+            keyring_dir.join("b.gpg"),
+            keyring_dir.join("c.asc")
+        ]
+    );
+    assert!(find_keyrings(std::slice::from_ref(&keyring)).is_empty());
+    std::fs::write(&keyring, b"").unwrap();
+    assert_eq!(find_keyrings(std::slice::from_ref(&keyring)), vec![keyring]);
+    assert!(find_keyrings(&[dir.path().join("none")]).is_empty());
+}
+
+#[test]
+fn test_dearmor() {
+    let armored = "-----BEGIN PGP PUBLIC KEY BLOCK-----\nComment: test key\n\nAQID\nBAU=\n=abcd\n-----END PGP PUBLIC KEY BLOCK-----\n";
+    assert_eq!(dearmor(armored).unwrap(), [1, 2, 3, 4, 5]);
+    let headerless =
+        "-----BEGIN PGP PUBLIC KEY BLOCK-----\n\nBgc=\n-----END PGP PUBLIC KEY BLOCK-----\n";
+    assert_eq!(
+        dearmor(&format!("{}{}", armored, headerless)).unwrap(),
+        [1, 2, 3, 4, 5, 6, 7]
+    );
+    assert!(dearmor("not a keyring").is_err());
+    assert!(dearmor(&armored.replace("AQID", "AQ*D")).is_err());
+}
+
 #[test]
 fn test_filter() {
     get_arch_name().unwrap();
@@ -143,22 +1138,12 @@ fn test_filter() {
     no.insert("not".to_owned());
     let topics = vec![
         TopicManifest {
-            enabled: false,
-            closed: false,
-            name: "test".to_string(),
-            description: None,
-            date: 0,
-            arch: all.clone(),
-            packages: vec![],
+            arch: all,
+            ..test_topic("test")
         },
         TopicManifest {
-            enabled: false,
-            closed: false,
-            name: "test2".to_string(),
-            description: None,
-            date: 0,
             arch: no,
-            packages: vec![],
+            ..test_topic("test2")
         },
     ];
     assert_eq!(filter_topics(topics).unwrap().len(), 1);
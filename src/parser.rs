@@ -1,16 +1,171 @@
+//! Parser for deb822 control files (dpkg status file and APT package indices)
+
 use anyhow::{anyhow, Result};
-use std::collections::HashSet;
+use indexmap::IndexMap;
+use std::{
+    cmp::Ordering,
+    collections::{hash_map::Entry, HashMap, HashSet},
+    io::BufRead,
+};
 use winnow::{
     ascii::space0,
-    combinator::{repeat, separated_pair, terminated},
-    token::{literal, one_of, take_until},
+    combinator::{alt, eof, preceded, repeat, separated_pair, terminated},
+    token::{literal, one_of, take_till, take_while},
     Parser, Result as IResult,
 };
 
+/// Fields of a single stanza as (name, value) pairs
+type Stanza<'a> = Vec<(&'a [u8], &'a [u8])>;
+
+/// Package selection state (the first word of the `Status` field)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SelectionState {
+    Unknown,
+    Install,
+    Hold,
+    Deinstall,
+    Purge,
+}
+
+/// Package error flag (the second word of the `Status` field)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorFlag {
+    Ok,
+    ReinstReq,
+}
+
+/// Package installation state (the third word of the `Status` field)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InstallState {
+    NotInstalled,
+    ConfigFiles,
+    HalfInstalled,
+    Unpacked,
+    HalfConfigured,
+    TriggersAwaited,
+    TriggersPending,
+    Installed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PackageStatus {
+    pub want: SelectionState,
+    pub flag: ErrorFlag,
+    pub state: InstallState,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PackageRecord {
+    pub package: String,
+    pub version: String,
+    pub architecture: String,
+    /// Only available in the dpkg status file
+    pub status: Option<PackageStatus>,
+    pub source: Option<String>,
+    /// All the fields in the stanza, multi-line values contain their continuation lines verbatim
+    pub fields: IndexMap<String, String>,
+}
+
+impl PackageStatus {
+    fn parse(value: &str) -> Option<Self> {
+        let mut words = value.split_ascii_whitespace();
+        let want = match words.next()? {
+            "unknown" => SelectionState::Unknown,
+            "install" => SelectionState::Install,
+            "hold" => SelectionState::Hold,
+            "deinstall" => SelectionState::Deinstall,
+            "purge" => SelectionState::Purge,
+            _ => return None,
+        };
+        let flag = match words.next()? {
+            "ok" => ErrorFlag::Ok,
+            "reinstreq" => ErrorFlag::ReinstReq,
+            _ => return None,
+        };
+        let state = match words.next()? {
+            "not-installed" => InstallState::NotInstalled,
+            "config-files" => InstallState::ConfigFiles,
+            "half-installed" => InstallState::HalfInstalled,
+            "unpacked" => InstallState::Unpacked,
+            "half-configured" => InstallState::HalfConfigured,
+            "triggers-awaited" => InstallState::TriggersAwaited,
+            "triggers-pending" => InstallState::TriggersPending,
+            "installed" => InstallState::Installed,
+            _ => return None,
+        };
+        if words.next().is_some() {
+            return None;
+        }
+
+        Some(PackageStatus { want, flag, state })
+    }
+
+    /// Whether the files of the package are (at least partially) present on the system
+    pub fn is_installed(&self) -> bool {
+        !matches!(
+            self.state,
+            InstallState::NotInstalled | InstallState::ConfigFiles
+        )
+    }
+}
+
+/// Strip the trailing whitespace (including CR from CRLF line endings) from each line of the value
+fn normalize_value(value: &str) -> String {
+    value
+        .split('\n')
+        .map(|line| line.trim_end())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+impl PackageRecord {
+    fn from_fields(fields: Vec<(&[u8], &[u8])>) -> Option<Self> {
+        let fields = fields
+            .into_iter()
+            .map(|(k, v)| {
+                (
+                    String::from_utf8_lossy(k).into_owned(),
+                    normalize_value(&String::from_utf8_lossy(v)),
+                )
+            })
+            .collect::<IndexMap<_, _>>();
+        let get = |name: &str| {
+            fields
+                .iter()
+                .find(|(k, _)| k.eq_ignore_ascii_case(name))
+                .map(|(_, v)| v.clone())
+        };
+
+        Some(PackageRecord {
+            package: get("Package")?,
+            version: get("Version").unwrap_or_default(),
+            architecture: get("Architecture").unwrap_or_default(),
+            status: get("Status").and_then(|s| PackageStatus::parse(&s)),
+            source: get("Source"),
+            fields,
+        })
+    }
+
+    /// Whether the files of the package are (at least partially) present on the system
+    pub fn is_installed(&self) -> bool {
+        self.status.is_some_and(|s| s.is_installed())
+    }
+
+    /// Returns the continuation lines of a multi-line field, without the leading whitespace
+    #[cfg(test)]
+    pub fn continuation_lines(&self, name: &str) -> Vec<&str> {
+        self.fields
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.lines().skip(1).map(|l| &l[1..]).collect())
+            .unwrap_or_default()
+    }
+}
+
 #[inline]
 fn key_name<'a>(input: &mut &'a [u8]) -> IResult<&'a [u8]> {
-    take_until(0.., ":")
-        .verify(|input: &[u8]| !input.is_empty() && input[0] != b'\n')
+    take_till(1.., [b':', b'\n'])
+        .verify(|input: &[u8]| !matches!(input[0], b' ' | b'\t' | b'\r' | b'#'))
         .parse_next(input)
 }
 
@@ -21,52 +176,246 @@ fn separator(input: &mut &[u8]) -> IResult<()> {
 
 #[inline]
 fn single_line<'a>(input: &mut &'a [u8]) -> IResult<&'a [u8]> {
-    take_until(0.., "\n").parse_next(input)
+    take_till(0.., b'\n').parse_next(input)
+}
+
+/// Line terminator, the last line of the file may not have one
+#[inline]
+fn line_end(input: &mut &[u8]) -> IResult<()> {
+    alt((literal("\n").void(), eof.void())).parse_next(input)
+}
+
+/// Continuation line of a multi-line field (starts with a space or a tab),
+/// lines containing only whitespace are treated as stanza separators instead
+#[inline]
+fn continuation_line(input: &mut &[u8]) -> IResult<()> {
+    (
+        literal("\n"),
+        one_of([b' ', b'\t']),
+        single_line.verify(|line: &[u8]| !line.trim_ascii().is_empty()),
+    )
+        .void()
+        .parse_next(input)
+}
+
+/// Field value including the continuation lines
+#[inline]
+fn field_value<'a>(input: &mut &'a [u8]) -> IResult<&'a [u8]> {
+    (
+        single_line,
+        repeat::<_, _, (), _, _>(0.., continuation_line),
+    )
+        .take()
+        .parse_next(input)
 }
 
 #[inline]
 fn key_value<'a>(input: &mut &'a [u8]) -> IResult<(&'a [u8], &'a [u8])> {
-    separated_pair(key_name, separator, single_line).parse_next(input)
+    separated_pair(key_name, separator, field_value).parse_next(input)
 }
 
 #[inline]
-fn single_package<'a>(input: &mut &'a [u8]) -> IResult<Vec<(&'a [u8], &'a [u8])>> {
-    repeat(1.., terminated(key_value, literal("\n"))).parse_next(input)
+fn comment_line(input: &mut &[u8]) -> IResult<()> {
+    (literal("#"), single_line, line_end)
+        .void()
+        .parse_next(input)
 }
 
 #[inline]
-fn extract_name<'a>(input: &mut &'a [u8]) -> IResult<&'a [u8]> {
-    let info = single_package(input)?;
-    let mut found: Option<&[u8]> = None;
-    for i in info {
-        if i.0 == &b"Package"[..] {
-            found = Some(i.1);
+fn single_package<'a>(input: &mut &'a [u8]) -> IResult<Stanza<'a>> {
+    repeat(
+        1..,
+        alt((
+            terminated(key_value, line_end).map(Some),
+            comment_line.map(|_| None),
+        )),
+    )
+    .fold(Vec::new, |mut acc, field| {
+        if let Some(field) = field {
+            acc.push(field);
         }
-        if i.0 == &b"Status"[..] && i.1.len() > 8 && i.1[..8] == b"install "[..] && found.is_some()
-        {
-            return Ok(found.unwrap());
+        acc
+    })
+    .parse_next(input)
+}
+
+/// Empty lines or lines containing only whitespace
+#[inline]
+fn blank_lines(input: &mut &[u8]) -> IResult<()> {
+    repeat(0.., (take_while(0.., [b' ', b'\t', b'\r']), literal("\n"))).parse_next(input)
+}
+
+#[inline]
+fn all_packages<'a>(input: &mut &'a [u8]) -> IResult<Vec<Stanza<'a>>> {
+    terminated(
+        repeat(0.., preceded(blank_lines, single_package)),
+        (blank_lines, take_while(0.., [b' ', b'\t', b'\r']), eof),
+    )
+    .parse_next(input)
+}
+
+/// Parse all the package stanzas in a dpkg status file or an APT Packages index
+pub fn parse_packages(input: &[u8]) -> Result<Vec<PackageRecord>> {
+    let stanzas = all_packages
+        .parse(input)
+        .map_err(|_| anyhow!("Failed to parse the package stanzas"))?;
+
+    Ok(stanzas
+        .into_iter()
+        .filter_map(PackageRecord::from_fields)
+        .collect())
+}
+
+/// Sort order of a character in the non-digit parts of a version (`~` sorts before anything,
+/// even the end of the part, letters sort before the other characters)
+#[inline]
+fn version_char_order(c: Option<u8>) -> i32 {
+    match c {
+        None => 0,
+        Some(b'~') => -1,
+        Some(c) if c.is_ascii_alphabetic() => i32::from(c),
+        Some(c) => i32::from(c) + 256,
+    }
+}
+
+/// Compare the upstream versions or the revisions of two versions, see `compare_versions`
+fn compare_version_part(mut a: &[u8], mut b: &[u8]) -> Ordering {
+    let non_digit = |s: &[u8]| s.first().copied().filter(|c| !c.is_ascii_digit());
+    let digits = |s: &[u8]| s.iter().take_while(|c| c.is_ascii_digit()).count();
+    while !a.is_empty() || !b.is_empty() {
+        while non_digit(a).is_some() || non_digit(b).is_some() {
+            let (ca, cb) = (non_digit(a), non_digit(b));
+            let order = version_char_order(ca).cmp(&version_char_order(cb));
+            if order != Ordering::Equal {
+                return order;
+            }
+            // both characters are the same
+            a = &a[1..];
+            b = &b[1..];
+        }
+        // numerical comparison of the digits
+        while a.first() == Some(&b'0') {
+            a = &a[1..];
+        }
+        while b.first() == Some(&b'0') {
+            b = &b[1..];
+        }
+        let (da, db) = (digits(a), digits(b));
+        let order = da.cmp(&db).then_with(|| a[..da].cmp(&b[..db]));
+        if order != Ordering::Equal {
+            return order;
         }
+        a = &a[da..];
+        b = &b[db..];
     }
 
-    Ok(&[])
+    Ordering::Equal
 }
 
-#[inline]
-pub fn extract_all_names<'a>(input: &mut &'a [u8]) -> IResult<Vec<&'a [u8]>> {
-    repeat(1.., terminated(extract_name, literal("\n"))).parse_next(input)
+/// Split a version into (epoch, upstream version, revision)
+fn split_version(version: &str) -> (u64, &str, &str) {
+    let (epoch, rest) = match version.split_once(':') {
+        Some((epoch, rest)) => (epoch.parse().unwrap_or(0), rest),
+        None => (0, version),
+    };
+    let (upstream, revision) = rest.rsplit_once('-').unwrap_or((rest, ""));
+
+    (epoch, upstream, revision)
+}
+
+/// Compare two package versions the way dpkg does
+pub fn compare_versions(a: &str, b: &str) -> Ordering {
+    let (a_epoch, a_upstream, a_revision) = split_version(a);
+    let (b_epoch, b_upstream, b_revision) = split_version(b);
+
+    a_epoch
+        .cmp(&b_epoch)
+        .then_with(|| compare_version_part(a_upstream.as_bytes(), b_upstream.as_bytes()))
+        .then_with(|| compare_version_part(a_revision.as_bytes(), b_revision.as_bytes()))
+}
+
+/// Add the version of the package to the map, unless a newer version is already there
+pub fn insert_newest(versions: &mut HashMap<String, String>, package: String, version: String) {
+    match versions.entry(package) {
+        Entry::Occupied(mut entry) => {
+            if compare_versions(&version, entry.get()) == Ordering::Greater {
+                entry.insert(version);
+            }
+        }
+        Entry::Vacant(entry) => {
+            entry.insert(version);
+        }
+    }
+}
+
+/// Returns a map of package names to versions from a dpkg status file or an APT Packages index,
+/// the newest version is kept when an index lists several versions of a package
+pub fn list_versions(input: &mut &[u8], installed_only: bool) -> Result<HashMap<String, String>> {
+    let mut versions = HashMap::new();
+    for package in parse_packages(input)? {
+        if !installed_only || package.is_installed() {
+            insert_newest(&mut versions, package.package, package.version);
+        }
+    }
+
+    Ok(versions)
 }
 
-pub fn list_installed(input: &mut &[u8]) -> Result<HashSet<String>> {
-    let names = extract_all_names(input);
-    let mut result: HashSet<String> = HashSet::new();
-    for name in names.map_err(|_| anyhow!("Failed to parse dpkg status file"))? {
-        if name.is_empty() {
+/// Find the installed versions of the wanted packages in a dpkg status file in a single pass
+///
+/// Unlike [`list_versions`], only the stanza being scanned is kept in memory, and scanning stops
+/// as soon as all the wanted packages have been found.
+pub fn find_installed<R: BufRead>(
+    mut reader: R,
+    wanted: &HashSet<&str>,
+) -> Result<HashMap<String, String>> {
+    let mut found = HashMap::new();
+    let mut line = Vec::with_capacity(256);
+    // only set when the package of the current stanza is wanted
+    let mut package: Option<String> = None;
+    let mut version = Vec::new();
+    let mut installed = false;
+
+    loop {
+        line.clear();
+        let eof = reader.read_until(b'\n', &mut line)? == 0;
+        let content = line.trim_ascii_end();
+        if content.is_empty() {
+            // end of the stanza
+            if let Some(name) = package.take() {
+                if installed && !found.contains_key(&name) {
+                    found.insert(name, String::from_utf8_lossy(&version).into_owned());
+                }
+            }
+            version.clear();
+            installed = false;
+            if eof || found.len() == wanted.len() {
+                break;
+            }
+            continue;
+        }
+        if matches!(content[0], b' ' | b'\t' | b'#') {
+            // continuation lines and comments
             continue;
         }
-        result.insert(String::from_utf8_lossy(name).to_string());
+        let sep = content
+            .iter()
+            .position(|c| *c == b':')
+            .ok_or_else(|| anyhow!("Failed to parse dpkg status file"))?;
+        let (key, value) = (&content[..sep], content[sep + 1..].trim_ascii_start());
+        if key.eq_ignore_ascii_case(b"Package") {
+            let name = String::from_utf8_lossy(value);
+            package = wanted.contains(name.as_ref()).then(|| name.into_owned());
+        } else if key.eq_ignore_ascii_case(b"Version") {
+            version.clear();
+            version.extend_from_slice(value);
+        } else if key.eq_ignore_ascii_case(b"Status") {
+            installed = PackageStatus::parse(&String::from_utf8_lossy(value))
+                .is_some_and(|s| s.is_installed());
+        }
     }
 
-    Ok(result)
+    Ok(found)
 }
 
 // tests
@@ -114,7 +463,7 @@ fn test_key_value() {
 fn test_package() {
     let test = &b"Package: zsync\nVersion: 0.6.2-1\nStatus: install ok installed\nArchitecture: amd64\nInstalled-Size: 256\n\n"[..];
     assert_eq!(
-        single_package(&mut test.as_ref()),
+        single_package(&mut &test[..]),
         Ok(vec![
             (&b"Package"[..], &b"zsync"[..]),
             (&b"Version"[..], &b"0.6.2-1"[..]),
@@ -123,15 +472,291 @@ fn test_package() {
             (&b"Installed-Size"[..], &b"256"[..])
         ])
     );
-    assert_eq!(extract_name(&mut test.as_ref()), Ok(&b"zsync"[..]));
+    let records = parse_packages(test).unwrap();
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0].package, "zsync");
+    assert_eq!(records[0].version, "0.6.2-1");
+    assert_eq!(records[0].architecture, "amd64");
+    assert!(records[0].is_installed());
 }
 
 #[test]
 fn test_multi_package() {
     let mut test =
         &b"Package: zsync\nStatus: b\n\nPackage: rsync\nStatus: install ok installed\n\n"[..];
+    let installed = list_versions(&mut test, true).unwrap();
+    assert_eq!(installed.len(), 1);
+    assert!(installed.contains_key("rsync"));
+}
+
+#[test]
+fn test_list_versions() {
+    let test = &b"Package: zsync\nStatus: deinstall ok config-files\nVersion: 0.6.2-1\n\nPackage: rsync\nStatus: install ok installed\nVersion: 3.2.7\n\n"[..];
+    let installed = list_versions(&mut &test[..], true).unwrap();
+    assert_eq!(installed.len(), 1);
+    assert_eq!(installed["rsync"], "3.2.7");
+    let all = list_versions(&mut &test[..], false).unwrap();
+    assert_eq!(all.len(), 2);
+    assert_eq!(all["zsync"], "0.6.2-1");
+}
+
+#[cfg(test)]
+const REALISTIC_STATUS: &[u8] = b"Package: bash
+Status: install ok installed
+Priority: required
+Section: shells
+Installed-Size: 7168
+Maintainer: AOSC OS Maintainers <maintainers@aosc.io>
+Architecture: amd64
+Version: 5.2.21-1
+Depends: glibc, ncurses, readline
+Conffiles:
+ /etc/bash.bashrc 3a9dd5f7b0c4c4e1b3eab4d4b9d3f2d0
+ /etc/skel/.bashrc 5dd1a6f7e1b3c1d0e1f2a3b4c5d6e7f8
+Description: The Bourne Again SHell
+ Bash is an sh-compatible shell that incorporates useful features
+ from the Korn shell and the C shell.
+ .
+ It is intended to conform to the IEEE POSIX P1003.2/ISO 9945.2 standard.
+
+Package: linux-kernel-lts
+Status: hold ok installed
+Architecture: amd64
+Source: linux-kernel (6.6.30)
+Version: 6.6.30-0
+Description: Linux kernel (LTS)
+
+Package: gimp
+Status: deinstall ok config-files
+Architecture: amd64
+Version: 2.10.36
+Conffiles:
+ /etc/gimp/2.0/gimprc 1c7f2a0e4d6b8a9c0d1e2f3a4b5c6d7e
+
+Package: vim
+Status: install reinstreq half-installed
+Architecture: amd64
+Version: 9.1.0
+Description:
+ Vi IMproved
+";
+
+#[test]
+fn test_realistic_status() {
+    let records = parse_packages(REALISTIC_STATUS).unwrap();
+    assert_eq!(records.len(), 4);
+    let bash = &records[0];
+    assert_eq!(bash.package, "bash");
+    assert_eq!(bash.version, "5.2.21-1");
+    assert_eq!(bash.architecture, "amd64");
+    assert_eq!(
+        bash.status,
+        Some(PackageStatus {
+            want: SelectionState::Install,
+            flag: ErrorFlag::Ok,
+            state: InstallState::Installed
+        })
+    );
+    assert_eq!(bash.source, None);
     assert_eq!(
-        extract_all_names(&mut test),
-        Ok(vec![&b""[..], &b"rsync"[..]])
+        bash.continuation_lines("Conffiles"),
+        vec![
+            "/etc/bash.bashrc 3a9dd5f7b0c4c4e1b3eab4d4b9d3f2d0",
+            "/etc/skel/.bashrc 5dd1a6f7e1b3c1d0e1f2a3b4c5d6e7f8"
+        ]
     );
+    assert_eq!(bash.continuation_lines("description").len(), 4);
+    assert!(bash.fields["Description"].starts_with("The Bourne Again SHell\n Bash"));
+
+    let kernel = &records[1];
+    assert_eq!(kernel.status.unwrap().want, SelectionState::Hold);
+    assert_eq!(kernel.source.as_deref(), Some("linux-kernel (6.6.30)"));
+    assert!(kernel.is_installed());
+
+    let gimp = &records[2];
+    assert_eq!(gimp.status.unwrap().state, InstallState::ConfigFiles);
+    assert!(!gimp.is_installed());
+
+    let vim = &records[3];
+    assert_eq!(vim.status.unwrap().flag, ErrorFlag::ReinstReq);
+    assert_eq!(vim.continuation_lines("Description"), vec!["Vi IMproved"]);
+
+    let installed = list_versions(&mut &REALISTIC_STATUS[..], true).unwrap();
+    assert_eq!(installed.len(), 3);
+    assert!(installed.contains_key("linux-kernel-lts"));
+    assert!(!installed.contains_key("gimp"));
+}
+
+#[test]
+fn test_malformed_status() {
+    assert!(parse_packages(b"Package: bash\nnot a field\n").is_err());
+    assert!(parse_packages(b"").unwrap().is_empty());
+}
+
+#[test]
+fn test_crlf_comments_and_whitespace() {
+    let test = b"# generated\r\nPackage: foo \r\nVersion:\t1.0-1\t\r\nDescription:\r\n short \r\n .\r\n# comment\r\nEmpty:\r\n \t\r\n\r\nPackage: bar\r\nVersion: 2.0";
+    let records = parse_packages(test).unwrap();
+    assert_eq!(records.len(), 2);
+    assert_eq!(records[0].package, "foo");
+    assert_eq!(records[0].version, "1.0-1");
+    assert_eq!(records[0].fields["Description"], "\n short\n .");
+    assert_eq!(records[0].fields["Empty"], "");
+    assert_eq!(records[1].package, "bar");
+    assert_eq!(records[1].version, "2.0");
+}
+
+/// Generate random stanzas and check that the parser recovers every field
+#[test]
+fn test_generated_stanzas() {
+    // xorshift64, fixed seed to keep the test reproducible
+    let mut state = 0x2545_f491_4f6c_dd1d_u64;
+    let mut next = move |bound: u64| {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state % bound
+    };
+    const NAME_CHARS: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-";
+    const VALUE_CHARS: &[u8] = b"abcXYZ0123 ._-+:~()<>@/,#";
+
+    for _ in 0..500 {
+        let newline: &[u8] = if next(2) == 0 { b"\n" } else { b"\r\n" };
+        let mut input = Vec::new();
+        let mut expected = Vec::new();
+        for _ in 0..(1 + next(4)) {
+            let mut fields = IndexMap::new();
+            fields.insert("Package".to_string(), format!("pkg{}", next(100_000)));
+            input.extend_from_slice(format!("Package: {}", fields["Package"]).as_bytes());
+            input.extend_from_slice(newline);
+            for _ in 0..next(6) {
+                if next(5) == 0 {
+                    input.extend_from_slice(b"# comment line");
+                    input.extend_from_slice(newline);
+                }
+                let mut name = String::new();
+                name.push(NAME_CHARS[next(52) as usize] as char);
+                for _ in 0..next(10) {
+                    name.push(NAME_CHARS[next(NAME_CHARS.len() as u64) as usize] as char);
+                }
+                if fields.contains_key(&name) || name.eq_ignore_ascii_case("package") {
+                    continue;
+                }
+                let mut value = String::new();
+                for _ in 0..next(20) {
+                    value.push(VALUE_CHARS[next(VALUE_CHARS.len() as u64) as usize] as char);
+                }
+                let value = value.trim().to_string();
+                let mut expected_value = value.clone();
+                input.extend_from_slice(
+                    format!("{}:{}{}", name, " ".repeat(next(3) as usize), value).as_bytes(),
+                );
+                input.extend_from_slice(" ".repeat(next(2) as usize).as_bytes());
+                input.extend_from_slice(newline);
+                for _ in 0..next(3) {
+                    let line = format!(" {}x{}", ".".repeat(next(2) as usize), next(1000));
+                    expected_value.push('\n');
+                    expected_value.push_str(&line);
+                    input.extend_from_slice(line.as_bytes());
+                    input.extend_from_slice("\t".repeat(next(2) as usize).as_bytes());
+                    input.extend_from_slice(newline);
+                }
+                fields.insert(name, expected_value);
+            }
+            expected.push(fields);
+            for _ in 0..(1 + next(2)) {
+                input.extend_from_slice(" ".repeat(next(2) as usize).as_bytes());
+                input.extend_from_slice(newline);
+            }
+        }
+
+        let records = parse_packages(&input)
+            .unwrap_or_else(|_| panic!("failed to parse {:?}", String::from_utf8_lossy(&input)));
+        assert_eq!(records.len(), expected.len());
+        for (record, fields) in records.iter().zip(expected.iter()) {
+            assert_eq!(&record.fields, fields);
+        }
+    }
+}
+
+#[test]
+fn test_find_installed() {
+    let wanted = ["bash", "gimp", "vim", "firefox"].iter().copied().collect();
+    let found = find_installed(REALISTIC_STATUS, &wanted).unwrap();
+    assert_eq!(found.len(), 2);
+    assert_eq!(found["bash"], "5.2.21-1");
+    assert_eq!(found["vim"], "9.1.0");
+
+    // stops at the first stanza, the malformed tail is never read
+    let wanted = ["foo"].iter().copied().collect();
+    let test = b"# generated\r\nStatus: install ok installed\r\nPackage: foo\r\n Version: 0\r\nVersion: 1.0-1 \r\n \t\r\nnot a field\n";
+    let found = find_installed(&test[..], &wanted).unwrap();
+    assert_eq!(found["foo"], "1.0-1");
+    assert!(find_installed(&test[..], &["bar"].iter().copied().collect()).is_err());
+}
+
+/// Compare the streaming lookup with the full parser on a large generated status file
+///
+/// Run with `cargo test --release -- --ignored --nocapture bench_find_installed`
+#[test]
+#[ignore]
+fn bench_find_installed() {
+    use std::time::Instant;
+
+    let mut input = Vec::new();
+    for i in 0..50_000 {
+        input.extend_from_slice(
+            format!(
+                "Package: pkg{i}\nStatus: install ok installed\nPriority: optional\nSection: libs\nInstalled-Size: 1024\nMaintainer: AOSC OS Maintainers <maintainers@aosc.io>\nArchitecture: amd64\nVersion: {i}.0-1\nDepends: glibc, pkg{}\nDescription: Generated package {i}\n This is a generated package.\n .\n It only exists for benchmarking.\n\n",
+                i / 2
+            )
+            .as_bytes(),
+        );
+    }
+    let names = (0..50)
+        .map(|i| format!("pkg{}", i * 997))
+        .collect::<Vec<_>>();
+    let wanted = names.iter().map(|n| n.as_str()).collect::<HashSet<_>>();
+    println!("status file size: {} bytes", input.len());
+
+    let start = Instant::now();
+    let versions = list_versions(&mut input.as_slice(), true).unwrap();
+    let full = names
+        .iter()
+        .filter_map(|n| Some((n.clone(), versions.get(n)?.clone())))
+        .collect::<HashMap<_, _>>();
+    println!("list_versions:  {:?}", start.elapsed());
+
+    let start = Instant::now();
+    let streamed = find_installed(input.as_slice(), &wanted).unwrap();
+    println!("find_installed: {:?}", start.elapsed());
+
+    assert_eq!(full, streamed);
+}
+
+#[test]
+fn test_compare_versions() {
+    use std::cmp::Ordering::*;
+
+    let cases = [
+        ("1.0", "1.0", Equal),
+        ("1.0", "1.0-0", Equal),
+        ("1.0", "1.00", Equal),
+        ("1.0", "1.1", Less),
+        ("1.10", "1.9", Greater),
+        ("1.0~rc1", "1.0", Less),
+        ("1.0~rc1", "1.0~rc2", Less),
+        ("1.0a", "1.0", Greater),
+        ("1.0a", "1.0+", Less),
+        ("1:0.1", "2.0", Greater),
+        ("6.6.1-0", "6.6.1-1", Less),
+        ("2.38-1", "2.38.1-0", Less),
+    ];
+    for (a, b, expected) in cases.iter().copied() {
+        assert_eq!(compare_versions(a, b), expected, "{} vs {}", a, b);
+        assert_eq!(compare_versions(b, a), expected.reverse(), "{} vs {}", b, a);
+    }
+    let index = b"Package: linux\nVersion: 6.6.2\n\nPackage: linux\nVersion: 6.6.10\n\nPackage: linux\nVersion: 6.6.9\n";
+    let versions = list_versions(&mut &index[..], false).unwrap();
+    assert_eq!(versions["linux"], "6.6.10");
 }
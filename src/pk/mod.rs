@@ -12,10 +12,13 @@ use packagekit_tx::TransactionProxy;
 use serde::Deserialize;
 use zbus::{export::ordered_stream::OrderedStreamExt, proxy, Connection, Result as zResult};
 
+use crate::config;
+
 #[derive(Deserialize, Debug)]
 pub struct PkPackage {
     pub info: u32,
     pub package_id: String,
+    #[allow(dead_code)]
     pub summary: String,
 }
 
@@ -128,22 +131,22 @@ async fn wait_for_exit_signal<Fut: Future<Output = zResult<()>>>(
     let mut destroy_signal_stream = proxy.receive_destroy().await?;
     // poll the future to start the transaction
     func.await?;
-    tokio::select!{
+    tokio::select! {
         v = async {
-            while let Some(e) = OrderedStreamExt::next(&mut error_signal_stream).await {
+            if let Some(e) = OrderedStreamExt::next(&mut error_signal_stream).await {
                 let args = e.args()?;
                 return Err(anyhow!("({}) {}", args.code, args.details));
             }
             Ok(())
         } => v,
         v = async {
-            if let Some(_) = OrderedStreamExt::next(&mut finish_signal_stream).await {
+            if OrderedStreamExt::next(&mut finish_signal_stream).await.is_some() {
                 return Ok(());
             }
             Ok(())
         } => v,
         v = async {
-            if let Some(_) = OrderedStreamExt::next(&mut destroy_signal_stream).await {
+            if OrderedStreamExt::next(&mut destroy_signal_stream).await.is_some() {
                 return Ok(());
             }
             Ok(())
@@ -165,7 +168,7 @@ async fn collect_packages<Fut: Future<Output = zResult<()>>>(
 
     // poll the future to start the transaction
     func.await?;
-    tokio::select!{
+    tokio::select! {
         v = async {
             while let Some(package) = OrderedStreamExt::next(&mut package_signal_stream).await {
                 let args = package.args()?;
@@ -178,20 +181,20 @@ async fn collect_packages<Fut: Future<Output = zResult<()>>>(
             Ok(())
         } => v,
         v = async {
-            while let Some(e) = OrderedStreamExt::next(&mut error_signal_stream).await {
+            if let Some(e) = OrderedStreamExt::next(&mut error_signal_stream).await {
                 let args = e.args()?;
                 return Err(anyhow!("({}) {}", args.code, args.details));
             }
             Ok(())
         } => v,
         v = async {
-            if let Some(_) = OrderedStreamExt::next(&mut finish_signal_stream).await {
+            if OrderedStreamExt::next(&mut finish_signal_stream).await.is_some() {
                 return Ok(());
             }
             Ok(())
         } => v,
         v = async {
-            if let Some(_) = OrderedStreamExt::next(&mut destroy_signal_stream).await {
+            if OrderedStreamExt::next(&mut destroy_signal_stream).await.is_some() {
                 return Ok(());
             }
             Ok(())
@@ -211,6 +214,20 @@ pub async fn connect_packagekit(conn: &Connection) -> zResult<PackageKitProxy<'_
     PackageKitProxy::new(conn).await
 }
 
+/// Forward the configured network proxy to PackageKit for the downloads of this session
+///
+/// PackageKit may refuse the proxy (e.g. by policy), the frontends report the error and go on
+/// without it.
+pub async fn forward_proxy(proxy: &PackageKitProxy<'_>) -> zResult<()> {
+    let network = &config::get().network;
+    if let Some(url) = &network.proxy {
+        let no_proxy = network.no_proxy.as_deref().unwrap_or_default();
+        proxy.set_proxy(url, url, "", "", no_proxy, "").await?;
+    }
+
+    Ok(())
+}
+
 /// A convient function to create a new PackageKit transaction session
 pub async fn create_transaction<'a>(
     proxy: &'a PackageKitProxy<'a>,
@@ -252,13 +269,17 @@ pub async fn find_stable_version_of(
 
     let mut candidates_map: HashMap<String, PkPackage> = HashMap::new();
     candidates_map.reserve(candidates.len());
+    let stable_prefix = config::get().repo.stable_prefix();
 
     for candidate in candidates {
         let candidate_parsed =
             parse_package_id(&candidate.package_id).ok_or_else(|| anyhow!("Invalid package id"))?;
         // skip packages that are not in the stable branch
-        if !candidate_parsed.data.starts_with("aosc-stable-")
-            && !candidate_parsed.data.starts_with("installed:aosc-stable-")
+        if !candidate_parsed.data.starts_with(&stable_prefix)
+            && !candidate_parsed
+                .data
+                .strip_prefix("installed:")
+                .is_some_and(|data| data.starts_with(&stable_prefix))
         {
             continue;
         }
@@ -388,21 +409,21 @@ async fn monitor_item_progress<Fut: Future<Output = zResult<()>>>(
             Ok(())
         } => v,
         v = async {
-            while let Some(e) = OrderedStreamExt::next(&mut error_signal_stream).await {
+            if let Some(e) = OrderedStreamExt::next(&mut error_signal_stream).await {
                 let args = e.args()?;
                 return Err(anyhow!("({}) {}", args.code, args.details));
             }
             Ok(())
         } => v,
         v = async {
-            if let Some(_) = OrderedStreamExt::next(&mut finish_signal_stream).await {
+            if OrderedStreamExt::next(&mut finish_signal_stream).await.is_some() {
                 progress_tx.send(PkDisplayProgress::Done)?;
                 return Ok(());
             }
             Ok(())
         } => v,
         v = async {
-            if let Some(_) = OrderedStreamExt::next(&mut destroy_signal_stream).await {
+            if OrderedStreamExt::next(&mut destroy_signal_stream).await.is_some() {
                 progress_tx.send(PkDisplayProgress::Done)?;
                 return Ok(());
             }
@@ -3,27 +3,32 @@ use std::{
     collections::{HashMap, HashSet},
     fmt::Write as WriteFmt,
     fs,
-    io::Write,
+    io::{BufReader, Write},
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
 };
 
-use crate::network::{TopicManifest, TopicManifests};
-use crate::parser::list_installed;
+use crate::history::{self, append_history, HistoryEvent};
+use crate::network::{normalize_mirror_url, TopicManifest, TopicManifests};
+use crate::parser::find_installed;
 use crate::pk::{
     create_transaction, find_stable_version_of, get_updated_packages, refresh_cache,
     PackageKitProxy,
 };
-use anyhow::Result;
+use crate::{config, fl, mirror};
+use anyhow::{anyhow, Result};
+use nix::unistd::Uid;
 use serde::{Deserialize, Serialize};
 use serde_json::{from_reader, to_string};
 
 const SOURCE_HEADER: &[u8] = b"# Generated by AOSC Topic Manager. DO NOT EDIT THIS FILE!\n";
-const SOURCE_PATH: &str = "/etc/apt/sources.list.d/atm.list";
-const SOURCE_PATH_NEW: &str = "/etc/apt/sources.list.d/atm.sources";
-const STATE_PATH: &str = "/var/lib/atm/state";
-const STATE_DIR: &str = "/var/lib/atm/";
-const DPKG_STATE: &str = "/var/lib/dpkg/status";
+const STATE_FILE: &str = "state";
+/// The new state file, moved over the state file once the source list is written
+const PENDING_STATE_FILE: &str = "state.pending";
+const BACKUP_DIR: &str = "backup";
+const BACKUP_MARKER: &str = "complete";
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub struct PreviousTopic {
     pub name: String,
     pub description: Option<String>,
@@ -34,16 +39,87 @@ pub struct PreviousTopic {
 
 type PreviousTopics = Vec<PreviousTopic>;
 
+#[inline]
+fn state_path() -> PathBuf {
+    config::get().paths.state_file(STATE_FILE)
+}
+
+#[inline]
+fn pending_state_path() -> PathBuf {
+    config::get().paths.state_file(PENDING_STATE_FILE)
+}
+
+#[inline]
+fn backup_dir() -> PathBuf {
+    config::get().paths.state_file(BACKUP_DIR)
+}
+
+/// Returns the files managed by atm: the source lists and the state file, along with their
+/// roles which name their backup copies
+fn managed_files() -> [(&'static str, PathBuf); 3] {
+    let paths = &config::get().paths;
+
+    [
+        ("source-list", paths.source_list.clone()),
+        ("source-list-deb822", paths.source_list_deb822.clone()),
+        ("state", state_path()),
+    ]
+}
+
+/// Flush the directory entries of the parent directory to the disk
+fn sync_parent_dir(path: &Path) -> Result<()> {
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        fs::File::open(parent)?.sync_all()?;
+    }
+
+    Ok(())
+}
+
+/// Replace the file with the given content, the file either keeps its old content
+/// or has the new content even if the system crashes in the middle of the write
+fn write_atomic(path: &Path, content: &[u8]) -> Result<()> {
+    let dir = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let mut f = tempfile::Builder::new().prefix(".atm-").tempfile_in(dir)?;
+    f.write_all(content)?;
+    // APT reads the source lists as an unprivileged user
+    f.as_file()
+        .set_permissions(fs::Permissions::from_mode(0o644))?;
+    f.as_file().sync_all()?;
+    f.persist(path).map_err(|e| e.error)?;
+
+    sync_parent_dir(path)
+}
+
+/// Move the file over another one, see `write_atomic`
+fn rename_atomic(from: &Path, to: &Path) -> Result<()> {
+    fs::rename(from, to)?;
+
+    sync_parent_dir(to)
+}
+
+/// Open the dpkg status database
+#[inline]
+fn open_dpkg_status() -> Result<BufReader<fs::File>> {
+    Ok(BufReader::new(fs::File::open(
+        &config::get().paths.dpkg_status,
+    )?))
+}
+
 /// Returns the packages need to be reinstalled
 pub fn close_topics(topics: &[TopicManifest]) -> Result<Vec<String>> {
-    let state_file = fs::read(DPKG_STATE)?;
-    let state_file_ref = &mut state_file.as_slice();
-    let installed = list_installed(state_file_ref)?;
+    let wanted = topics
+        .iter()
+        .flat_map(|t| t.packages.iter().map(|p| p.as_str()))
+        .collect::<HashSet<_>>();
+    let installed = find_installed(open_dpkg_status()?, &wanted)?;
     let mut remove = Vec::new();
 
     for topic in topics {
         for package in topic.packages.iter() {
-            if installed.contains(package) {
+            if installed.contains_key(package) {
                 remove.push(package.clone());
             }
         }
@@ -52,9 +128,38 @@ pub fn close_topics(topics: &[TopicManifest]) -> Result<Vec<String>> {
     Ok(remove)
 }
 
+/// Version changes of a package provided by a topic
+#[derive(Serialize, Debug, Clone)]
+pub struct PackageDiff {
+    pub name: String,
+    /// Currently installed version, `None` if not installed
+    pub installed: Option<String>,
+    /// Version provided by the topic, `None` if not found in the topic repository
+    pub topic: Option<String>,
+}
+
+/// Compare the packages of the topic with the installed packages
+pub fn get_topic_diff(
+    topic: &TopicManifest,
+    topic_versions: &HashMap<String, String>,
+) -> Result<Vec<PackageDiff>> {
+    let wanted = topic.packages.iter().map(|p| p.as_str()).collect();
+    let installed = find_installed(open_dpkg_status()?, &wanted)?;
+
+    Ok(topic
+        .packages
+        .iter()
+        .map(|name| PackageDiff {
+            name: name.clone(),
+            installed: installed.get(name).cloned(),
+            topic: topic_versions.get(name).cloned(),
+        })
+        .collect())
+}
+
 /// Returns the list of enrolled topics
 fn get_previous_topics() -> Result<PreviousTopics> {
-    Ok(from_reader(fs::File::open(STATE_PATH)?)?)
+    Ok(from_reader(fs::File::open(state_path())?)?)
 }
 
 pub fn get_display_listing(current: TopicManifests) -> TopicManifests {
@@ -66,8 +171,7 @@ pub fn get_display_listing(current: TopicManifests) -> TopicManifests {
         lookup.insert(topic.name.clone(), topic);
     }
 
-    let mut concatenated = Vec::new();
-    concatenated.reserve(prev.len() + current_len);
+    let mut concatenated = Vec::with_capacity(prev.len() + current_len);
     for topic in prev {
         if let Some(topic) = lookup.get_mut(&topic.name) {
             topic.enabled = true;
@@ -91,6 +195,61 @@ pub fn get_display_listing(current: TopicManifests) -> TopicManifests {
     concatenated
 }
 
+/// Whether the topic name is safe to be written to the APT sources
+pub fn is_valid_topic_name(name: &str) -> bool {
+    name.len() <= 128
+        && name.starts_with(|c: char| c.is_ascii_alphanumeric())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '+' | '-'))
+}
+
+/// Resolve the topic names requested by an unprivileged user against the enrolled topics and,
+/// if some of them are not enrolled yet, the verified topic manifest of the mirror (or its
+/// cached copy)
+///
+/// The manifest is verified with the policy of the privileged side: the system configuration,
+/// without the `--allow-unsigned` of the caller.
+pub async fn resolve_topics(
+    client: &reqwest::Client,
+    mirror_url: &str,
+    names: &[&str],
+) -> Result<TopicManifests> {
+    if let Some(name) = names
+        .iter()
+        .copied()
+        .find(|name| !is_valid_topic_name(name))
+    {
+        return Err(anyhow!(fl!("topic-invalid-name", name = name)));
+    }
+    let enrolled = get_display_listing(Vec::new());
+    // leaving topics or keeping the enrolled ones does not need the manifest
+    let listing = if names
+        .iter()
+        .all(|name| enrolled.iter().any(|t| t.name == *name))
+    {
+        enrolled
+    } else {
+        get_display_listing(mirror::load_topics(client, mirror_url).await?.topics)
+    };
+    if let Some(name) = names
+        .iter()
+        .copied()
+        .find(|name| !listing.iter().any(|t| t.name == *name))
+    {
+        return Err(anyhow!(fl!("topic-not-found", name = name)));
+    }
+
+    Ok(listing
+        .into_iter()
+        .filter(|t| names.contains(&t.name.as_str()))
+        .map(|mut t| {
+            t.enabled = true;
+            t
+        })
+        .collect())
+}
+
 fn save_as_previous_topics(current: &[&TopicManifest]) -> Result<String> {
     let mut previous_topics = Vec::new();
     for topic in current {
@@ -108,11 +267,17 @@ fn save_as_previous_topics(current: &[&TopicManifest]) -> Result<String> {
     Ok(to_string(&previous_topics)?)
 }
 
-fn normalize_url(url: &str) -> Cow<str> {
-    if url.ends_with('/') {
+fn normalize_url(url: &str) -> Cow<'_, str> {
+    // local repositories are written as `file:` URIs
+    let url = if url.starts_with('/') {
+        Cow::Owned(normalize_mirror_url(url))
+    } else {
         Cow::Borrowed(url)
+    };
+    if url.ends_with('/') {
+        url
     } else {
-        let mut url = Cow::from(url);
+        let mut url = url;
         url.to_mut().push('/');
         url
     }
@@ -122,24 +287,28 @@ fn make_topic_list_deb822(topics: &[&TopicManifest], mirror_url: &str) -> String
     let suites = topics
         .iter()
         .map(|t| &t.name)
-        .fold(String::new(), |acc, s| acc + " " + &s);
+        .fold(String::new(), |acc, s| acc + " " + s);
 
     format!(
-        "Types: deb\nURIs: {}debs\nSuites: {}\nComponents: main\n\n",
-        mirror_url, suites
+        "Types: deb\nURIs: {}debs\nSuites: {}\nComponents: {}\n\n",
+        normalize_url(mirror_url),
+        suites,
+        config::get().repo.components.join(" ")
     )
 }
 
 fn make_topic_list(topics: &[&TopicManifest], mirror_url: &str) -> String {
     let mut output = String::with_capacity(1024);
+    let components = config::get().repo.components.join(" ");
 
     for topic in topics {
         writeln!(
             &mut output,
-            "# Topic `{}`\ndeb {}debs {} main",
+            "# Topic `{}`\ndeb {}debs {} {}",
             topic.name,
             normalize_url(mirror_url),
-            topic.name
+            topic.name,
+            components
         )
         .unwrap();
         // this will only happen when malloc() fails.
@@ -149,42 +318,327 @@ fn make_topic_list(topics: &[&TopicManifest], mirror_url: &str) -> String {
     output
 }
 
-pub fn write_source_list(topics: &[&TopicManifest], mirror_url: &str) -> Result<()> {
-    let new_f = fs::File::options()
-        .truncate(true)
-        .write(true)
-        .open(SOURCE_PATH_NEW);
-    let (mut f, is_deb822) = match new_f {
-        Ok(f) => {
-            // also remove the old source list if it exists (to avoid conflicts)
-            fs::remove_file(SOURCE_PATH).ok();
-            (f, true)
+fn make_source_list(topics: &[&TopicManifest], mirror_url: &str, is_deb822: bool) -> String {
+    if is_deb822 {
+        make_topic_list_deb822(topics, mirror_url)
+    } else {
+        make_topic_list(topics, mirror_url)
+    }
+}
+
+/// Check that the topic names and the mirror can be written to the APT sources as they are
+fn validate_source_list(topics: &[&TopicManifest], mirror_url: &str) -> Result<()> {
+    mirror::validate_mirror_url(mirror_url)?;
+    if let Some(topic) = topics.iter().find(|t| !is_valid_topic_name(&t.name)) {
+        return Err(anyhow!(fl!(
+            "topic-invalid-name",
+            name = topic.name.as_str()
+        )));
+    }
+
+    Ok(())
+}
+
+/// Returns the content of the source list that would be written by `write_source_list`
+pub fn preview_source_list(topics: &[&TopicManifest], mirror_url: &str) -> Result<String> {
+    validate_source_list(topics, mirror_url)?;
+    let is_deb822 = config::get().paths.source_list_deb822.exists();
+    let mut output = String::from_utf8_lossy(SOURCE_HEADER).into_owned();
+    output.push_str(&make_source_list(topics, mirror_url, is_deb822));
+
+    Ok(output)
+}
+
+/// Returns the path of the backup copy of the configuration file with the given role
+fn backup_path_of(role: &str) -> PathBuf {
+    // the configured paths may share a file name, the roles are unique
+    backup_dir().join(role)
+}
+
+/// Take a snapshot of the current source lists and the state file
+fn backup_source_list() -> Result<()> {
+    let backup_dir = backup_dir();
+    // keep the configuration from before a failed change until it is restored,
+    // the current configuration is the broken one
+    if backup_dir.join(BACKUP_MARKER).exists() && history::last_change_failed()? {
+        return Ok(());
+    }
+    if backup_dir.exists() {
+        fs::remove_dir_all(&backup_dir)?;
+    }
+    fs::create_dir_all(&backup_dir)?;
+    for (role, path) in managed_files() {
+        if path.exists() {
+            fs::copy(&path, backup_path_of(role))?;
         }
-        Err(_) => (fs::File::create(SOURCE_PATH)?, false),
-    };
-    f.write_all(SOURCE_HEADER)?;
-    f.write_all(
-        if is_deb822 {
-            make_topic_list_deb822(topics, mirror_url)
-        } else {
-            make_topic_list(topics, mirror_url)
+    }
+    // the snapshot is only usable when all the files are copied
+    fs::File::create(backup_dir.join(BACKUP_MARKER))?;
+
+    Ok(())
+}
+
+/// Restore the source lists and the state file from the snapshot taken
+/// before the last `write_source_list` call, `requester` is recorded in the history
+pub fn restore_source_list(requester: Uid) -> Result<()> {
+    if !backup_dir().join(BACKUP_MARKER).exists() {
+        return Err(anyhow!(fl!("restore-no-snapshot")));
+    }
+    // the snapshot replaces any unfinished change
+    if pending_state_path().exists() {
+        fs::remove_file(pending_state_path())?;
+    }
+    for (role, path) in managed_files() {
+        let backup = backup_path_of(role);
+        if backup.exists() {
+            write_atomic(&path, &fs::read(backup)?)?;
+        } else if path.exists() {
+            // the file did not exist before the change
+            fs::remove_file(&path)?;
+            sync_parent_dir(&path)?;
         }
-        .as_bytes(),
-    )?;
+    }
+    record_history(HistoryEvent::Restored, requester);
+
+    Ok(())
+}
+
+/// Append the event to the history, the change has already been made at this point
+/// and is not reported as failed if the history cannot be written
+fn record_history(event: HistoryEvent, requester: Uid) {
+    if let Err(e) = append_history(event, requester) {
+        eprintln!("{}", fl!("history-record-failure", error = e.to_string()));
+    }
+}
+
+/// Returns the names of the (added, removed) topics compared to the enrolled topics
+fn diff_enrolled_topics(
+    topics: &[&TopicManifest],
+    previous: &[PreviousTopic],
+) -> (Vec<String>, Vec<String>) {
+    let added = topics
+        .iter()
+        .filter(|t| t.enabled && !previous.iter().any(|p| p.name == t.name))
+        .map(|t| t.name.clone())
+        .collect();
+    let removed = previous
+        .iter()
+        .filter(|p| !topics.iter().any(|t| t.enabled && t.name == p.name))
+        .map(|p| p.name.clone())
+        .collect();
 
-    fs::create_dir_all(STATE_DIR)?;
-    let mut f = fs::File::create(STATE_PATH)?;
-    f.write_all(save_as_previous_topics(topics)?.as_bytes())?;
+    (added, removed)
+}
+
+/// Enroll into the enabled topics, `requester` is recorded in the history
+pub fn write_source_list(
+    topics: &[&TopicManifest],
+    mirror_url: &str,
+    requester: Uid,
+) -> Result<()> {
+    validate_source_list(topics, mirror_url)?;
+    let previous = get_previous_topics().unwrap_or_default();
+    let (added, removed) = diff_enrolled_topics(topics, &previous);
+    backup_source_list()?;
+    let paths = &config::get().paths;
+    // the new state is only moved into place once the source list is written,
+    // see `check_consistency` for the recovery of an interrupted write
+    fs::create_dir_all(&paths.state_dir)?;
+    write_atomic(
+        &pending_state_path(),
+        save_as_previous_topics(topics)?.as_bytes(),
+    )?;
+    let is_deb822 = paths.source_list_deb822.exists();
+    let mut content = SOURCE_HEADER.to_vec();
+    content.extend_from_slice(make_source_list(topics, mirror_url, is_deb822).as_bytes());
+    if is_deb822 {
+        write_atomic(&paths.source_list_deb822, &content)?;
+        // also remove the old source list if it exists (to avoid conflicts)
+        fs::remove_file(&paths.source_list).ok();
+    } else {
+        write_atomic(&paths.source_list, &content)?;
+    }
+    rename_atomic(&pending_state_path(), &state_path())?;
+    record_history(
+        HistoryEvent::TopicsChanged {
+            added,
+            removed,
+            mirror: mirror_url.to_string(),
+            previous,
+        },
+        requester,
+    );
 
     Ok(())
 }
 
+/// Returns the suites enabled in the source list written by atm,
+/// or `None` if the source list has been replaced by something else
+fn read_enrolled_suites(files: &StateFiles) -> Result<Option<HashSet<String>>> {
+    let (path, is_deb822) = if files.source_list_deb822.exists() {
+        (&files.source_list_deb822, true)
+    } else {
+        (&files.source_list, false)
+    };
+    let content = match fs::read(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Some(HashSet::new())),
+        Err(e) => return Err(e.into()),
+    };
+    if !content.starts_with(SOURCE_HEADER) {
+        return Ok(None);
+    }
+
+    Ok(Some(parse_enrolled_suites(
+        &String::from_utf8_lossy(&content),
+        is_deb822,
+    )))
+}
+
+/// Returns the suites listed in the content of a source list made by `make_source_list`
+fn parse_enrolled_suites(content: &str, is_deb822: bool) -> HashSet<String> {
+    let lines = content.lines().map(|l| l.trim());
+    if is_deb822 {
+        lines
+            .filter_map(|l| l.strip_prefix("Suites:"))
+            .flat_map(|l| l.split_whitespace())
+            .map(|s| s.to_owned())
+            .collect()
+    } else {
+        lines
+            .filter(|l| l.starts_with("deb "))
+            .filter_map(|l| l.split_whitespace().nth(2))
+            .map(|s| s.to_owned())
+            .collect()
+    }
+}
+
+/// The source lists and the state files kept consistent by `check_consistency`
+struct StateFiles {
+    source_list: PathBuf,
+    source_list_deb822: PathBuf,
+    state: PathBuf,
+    pending: PathBuf,
+}
+
+impl StateFiles {
+    fn configured() -> Self {
+        let paths = &config::get().paths;
+
+        StateFiles {
+            source_list: paths.source_list.clone(),
+            source_list_deb822: paths.source_list_deb822.clone(),
+            state: state_path(),
+            pending: pending_state_path(),
+        }
+    }
+}
+
+/// Outcome of `check_consistency`
+#[derive(Debug, PartialEq, Eq)]
+pub enum StateRepair {
+    /// The state file matches the source list
+    Consistent,
+    /// The state file has been made to follow the source list
+    Repaired {
+        /// The state file could not be parsed and has been replaced
+        unreadable: bool,
+        /// Topics of the source list whose details were lost, stub entries stand for them
+        stubs: Vec<String>,
+    },
+}
+
+/// Detect and repair a mismatch between the source list and the state file,
+/// left by an interrupted change (requires root)
+///
+/// The source list is what APT uses, so the state file is made to follow it.
+pub fn check_consistency() -> Result<StateRepair> {
+    repair_state_files(&StateFiles::configured())
+}
+
+fn repair_state_files(files: &StateFiles) -> Result<StateRepair> {
+    let suites = match read_enrolled_suites(files)? {
+        Some(suites) => suites,
+        None => return Ok(StateRepair::Consistent),
+    };
+    let names_of = |topics: &PreviousTopics| {
+        topics
+            .iter()
+            .map(|t| t.name.clone())
+            .collect::<HashSet<_>>()
+    };
+    let mut repaired = false;
+    if files.pending.exists() {
+        let pending_topics: Option<PreviousTopics> = fs::File::open(&files.pending)
+            .ok()
+            .and_then(|f| from_reader(f).ok());
+        match pending_topics {
+            // the source list has been written, finish the change
+            Some(topics) if names_of(&topics) == suites => {
+                rename_atomic(&files.pending, &files.state)?
+            }
+            // otherwise the change did not happen
+            _ => fs::remove_file(&files.pending)?,
+        }
+        repaired = true;
+    }
+    let state = match fs::File::open(&files.state) {
+        Ok(f) => from_reader(f).ok(),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Some(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    if state.as_ref().map(names_of).as_ref() == Some(&suites) {
+        return Ok(if repaired {
+            StateRepair::Repaired {
+                unreadable: false,
+                stubs: Vec::new(),
+            }
+        } else {
+            StateRepair::Consistent
+        });
+    }
+    let unreadable = state.is_none();
+    // keep what is known about the topics still in the source list
+    let mut state = state
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|t| suites.contains(&t.name))
+        .collect::<PreviousTopics>();
+    let mut stubs = suites
+        .iter()
+        .filter(|s| !state.iter().any(|t| &t.name == *s))
+        .cloned()
+        .collect::<Vec<_>>();
+    stubs.sort();
+    state.extend(stubs.iter().map(|name| PreviousTopic {
+        name: name.clone(),
+        description: None,
+        date: 0,
+        packages: Vec::new(),
+    }));
+    if let Some(dir) = files.state.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    write_atomic(&files.state, to_string(&state)?.as_bytes())?;
+
+    Ok(StateRepair::Repaired { unreadable, stubs })
+}
+
 pub async fn switch_topics(
     proxy: &PackageKitProxy<'_>,
     closed: &[TopicManifest],
 ) -> Result<(Vec<String>, Vec<String>)> {
     let tx_proxy = create_transaction(proxy).await?;
     refresh_cache(&tx_proxy).await?;
+
+    resolve_switch_topics(proxy, closed).await
+}
+
+/// Same as `switch_topics`, but resolves against the current package cache without refreshing it
+pub async fn resolve_switch_topics(
+    proxy: &PackageKitProxy<'_>,
+    closed: &[TopicManifest],
+) -> Result<(Vec<String>, Vec<String>)> {
     let removed = close_topics(closed)?;
     let removed = removed.iter().map(|x| x.as_str()).collect::<Vec<_>>();
     let tx_proxy = create_transaction(proxy).await?;
@@ -199,3 +653,145 @@ pub async fn switch_topics(
 
     Ok((not_found, updated))
 }
+
+#[test]
+fn test_topic_names() {
+    assert!(is_valid_topic_name("kernel-6.6"));
+    assert!(is_valid_topic_name("gcc+libc_14"));
+    assert!(!is_valid_topic_name(""));
+    assert!(!is_valid_topic_name("-kernel"));
+    assert!(!is_valid_topic_name(
+        "kernel main\ndeb http://evil.example.com/ stable"
+    ));
+    assert!(!is_valid_topic_name("../kernel"));
+    assert!(!is_valid_topic_name(&"a".repeat(129)));
+}
+
+#[test]
+fn test_make_source_list() {
+    use crate::network::test_topic;
+
+    let topic = TopicManifest {
+        enabled: true,
+        ..test_topic("kernel-6.6")
+    };
+    assert_eq!(
+        make_source_list(&[&topic], "https://repo.aosc.io", false),
+        "# Topic `kernel-6.6`\ndeb https://repo.aosc.io/debs kernel-6.6 main\n"
+    );
+    assert_eq!(
+        make_source_list(&[&topic], "/mnt/aosc", false),
+        "# Topic `kernel-6.6`\ndeb file:///mnt/aosc/debs kernel-6.6 main\n"
+    );
+    assert_eq!(
+        make_source_list(&[&topic], "file:///mnt/aosc/", true),
+        "Types: deb\nURIs: file:///mnt/aosc/debs\nSuites:  kernel-6.6\nComponents: main\n\n"
+    );
+}
+
+#[test]
+fn test_parse_enrolled_suites() {
+    use crate::network::test_topic;
+
+    let topic = |name: &str| TopicManifest {
+        enabled: true,
+        ..test_topic(name)
+    };
+    let topics = [topic("kernel-6.6"), topic("gnome-46")];
+    let topics = topics.iter().collect::<Vec<_>>();
+    let expected = ["kernel-6.6", "gnome-46"]
+        .iter()
+        .map(|s| s.to_string())
+        .collect::<HashSet<_>>();
+    for is_deb822 in [false, true].iter().copied() {
+        let content = make_source_list(&topics, "https://repo.aosc.io/", is_deb822);
+        assert_eq!(parse_enrolled_suites(&content, is_deb822), expected);
+        let content = make_source_list(&[], "https://repo.aosc.io/", is_deb822);
+        assert!(parse_enrolled_suites(&content, is_deb822).is_empty());
+    }
+}
+
+#[test]
+fn test_write_atomic() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("atm.list");
+    write_atomic(&path, b"first").unwrap();
+    write_atomic(&path, b"second").unwrap();
+    assert_eq!(fs::read(&path).unwrap(), b"second");
+    assert_eq!(
+        fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+        0o644
+    );
+    // no temporary file is left behind
+    assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+}
+
+#[test]
+fn test_repair_state_files() {
+    let dir = tempfile::tempdir().unwrap();
+    let files = StateFiles {
+        source_list: dir.path().join("atm.list"),
+        source_list_deb822: dir.path().join("atm.sources"),
+        state: dir.path().join("state"),
+        pending: dir.path().join("state.pending"),
+    };
+    let topic = |name: &str| PreviousTopic {
+        name: name.to_owned(),
+        description: Some(name.to_owned()),
+        date: 1,
+        packages: vec!["linux-kernel".to_owned()],
+    };
+    let write_topics = |path: &Path, names: &[&str]| {
+        let topics = names.iter().map(|n| topic(n)).collect::<PreviousTopics>();
+        fs::write(path, to_string(&topics).unwrap()).unwrap();
+    };
+    let read_topics =
+        || -> PreviousTopics { from_reader(fs::File::open(&files.state).unwrap()).unwrap() };
+    let mut source_list = SOURCE_HEADER.to_vec();
+    source_list.extend_from_slice(b"deb https://repo.aosc.io/debs kernel-6.6 main\n");
+    fs::write(&files.source_list, &source_list).unwrap();
+
+    // the source list was written before the interruption, the change is finished
+    write_topics(&files.state, &[]);
+    write_topics(&files.pending, &["kernel-6.6"]);
+    assert_eq!(
+        repair_state_files(&files).unwrap(),
+        StateRepair::Repaired {
+            unreadable: false,
+            stubs: vec![]
+        }
+    );
+    assert!(!files.pending.exists());
+    assert_eq!(read_topics(), vec![topic("kernel-6.6")]);
+    assert_eq!(repair_state_files(&files).unwrap(), StateRepair::Consistent);
+
+    // the source list was not written, the change is dropped
+    write_topics(&files.pending, &["kernel-6.6", "gnome-46"]);
+    assert!(matches!(
+        repair_state_files(&files).unwrap(),
+        StateRepair::Repaired { .. }
+    ));
+    assert!(!files.pending.exists());
+    assert_eq!(read_topics(), vec![topic("kernel-6.6")]);
+
+    // the details of the topics missing from the state are lost
+    fs::write(&files.state, "not json").unwrap();
+    assert_eq!(
+        repair_state_files(&files).unwrap(),
+        StateRepair::Repaired {
+            unreadable: true,
+            stubs: vec!["kernel-6.6".to_owned()]
+        }
+    );
+    assert_eq!(read_topics()[0].name, "kernel-6.6");
+    assert_eq!(read_topics()[0].date, 0);
+
+    // source lists not written by atm are left alone
+    fs::write(
+        &files.source_list,
+        "deb https://repo.aosc.io/debs stable main\n",
+    )
+    .unwrap();
+    fs::write(&files.state, "not json").unwrap();
+    assert_eq!(repair_state_files(&files).unwrap(), StateRepair::Consistent);
+}